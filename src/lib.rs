@@ -1,7 +1,7 @@
-#![feature(asm)]
 #![feature(const_fn)]
 #![feature(pattern)]
 #![cfg_attr(test, feature(test))]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 //! A tiny library to efficiently search strings for substrings or
 //! sets of ASCII characters.
@@ -28,221 +28,729 @@
 //! assert_eq!(&colors, &["red", "blue", "green"]);
 //! ```
 
-use std::cmp::min;
-use std::fmt;
-use std::str::pattern::{Pattern, Searcher, SearchStep};
+#[cfg(any(feature = "std", test))]
+extern crate core;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "bstr")]
+extern crate bstr;
+#[cfg(feature = "winnow")]
+extern crate winnow;
+
+use core::cmp::min;
+use core::convert::TryFrom;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::iter::{FromIterator, FusedIterator};
+use core::mem::MaybeUninit;
+use core::ops::Range;
+use core::slice;
+use core::str::pattern::{Pattern, Searcher, SearchStep};
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(feature = "bstr")]
+use bstr::BStr;
+#[cfg(feature = "winnow")]
+use winnow::error::{ErrMode, ModalResult, ParserError};
+#[cfg(feature = "winnow")]
+use winnow::stream::Stream;
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead};
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__m128i, _mm_cmpestri, _mm_cmpestrm, _mm_cvtsi128_si64, _mm_loadu_si128,
+                        _mm_set_epi64x, _SIDD_CMP_EQUAL_ANY, _SIDD_CMP_EQUAL_ORDERED,
+                        _SIDD_CMP_RANGES, _SIDD_UBYTE_OPS};
+
+/// Compare a haystack against any of the bytes in a needle.
+#[cfg(target_arch = "x86_64")]
+const EQUAL_ANY: i32 = _SIDD_UBYTE_OPS | _SIDD_CMP_EQUAL_ANY;
+
+/// Compare a haystack against all of the bytes in a needle, in order.
+#[cfg(target_arch = "x86_64")]
+const EQUAL_ORDERED: i32 = _SIDD_UBYTE_OPS | _SIDD_CMP_EQUAL_ORDERED;
+
+/// Compare a haystack against pairs of inclusive bounds in a needle.
+#[cfg(target_arch = "x86_64")]
+const RANGES: i32 = _SIDD_UBYTE_OPS | _SIDD_CMP_RANGES;
+
+#[cfg(target_arch = "x86_64")]
+mod packed {
+    /// A single PCMPxSTRx comparison of a needle against 16 bytes of a
+    /// haystack, which `UnalignedByteSliceHandler` repeats across a whole
+    /// byte slice.
+    ///
+    /// Both methods may read all 16 bytes they are pointed at, even when
+    /// fewer of them belong to the byte slice. The handler only ever
+    /// points at 16-byte-aligned blocks, which never cross a page
+    /// boundary.
+    pub trait PackedCompareOperation {
+        /// Compare the 16-byte-aligned block at `ptr` and return a mask
+        /// with bit `i` set when byte `i` of the block matches. The byte
+        /// slice starts `offset` bytes into the block and has `len` bytes
+        /// left, so no bit at or past `offset + len` may be set. Bits
+        /// before `offset` are ignored.
+        ///
+        /// ### Safety
+        ///
+        /// `ptr` must be valid to read 16 bytes from.
+        unsafe fn initial(&self, ptr: *const u8, offset: usize, len: usize) -> u64;
+
+        /// Compare the 16 bytes at `ptr + offset`, of which only the
+        /// first `len` (saturating at 16) belong to the byte slice, and
+        /// return the index of the first match or 16 if there is none.
+        ///
+        /// ### Safety
+        ///
+        /// `ptr + offset` must be valid to read 16 bytes from.
+        unsafe fn body(&self, ptr: *const u8, offset: usize, len: usize) -> u32;
+    }
 
-trait PackedCompareOperation {
-    // Returns a mask
-    unsafe fn initial(&self, ptr: *const u8, offset: usize, len: usize) -> u64;
-    // Returns an index
-    unsafe fn body(&self, ptr: *const u8, offset: usize, len: usize) -> u32;
-}
+    enum InitialMatch {
+        Complete(Option<usize>),
+        Incomplete(usize),
+    }
 
-#[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-enum InitialMatch {
-    Complete(Option<usize>),
-    Incomplete(usize),
-}
+    /// Searches a byte slice with a `PackedCompareOperation`, taking care
+    /// of the leading bytes that are not 16-byte-aligned. Every index
+    /// returned is relative to the start of the byte slice.
+    pub struct UnalignedByteSliceHandler<T> {
+        pub(crate) operation: T,
+    }
 
-#[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-struct UnalignedByteSliceHandler<T> {
-    operation: T,
-}
+    impl<T> UnalignedByteSliceHandler<T>
+        where T: PackedCompareOperation
+    {
+        /// Create a handler that searches with `operation`.
+        ///
+        /// ### Safety
+        ///
+        /// The CPU must support every instruction that `operation` uses,
+        /// as the searching methods call it without checking.
+        #[cfg(feature = "unstable")]
+        pub unsafe fn new(operation: T) -> UnalignedByteSliceHandler<T> {
+            UnalignedByteSliceHandler { operation: operation }
+        }
 
-#[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-impl<T> UnalignedByteSliceHandler<T>
-    where T: PackedCompareOperation
-{
-    #[inline]
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn find(&self, haystack: &[u8]) -> Option<usize> {
-        let mut len = haystack.len();
+        /// Find the index of the first match.
+        #[inline]
+        #[must_use]
+        pub fn find(&self, haystack: &[u8]) -> Option<usize> {
+            let mut len = haystack.len();
 
-        if len == 0 {
-            return None;
+            if len == 0 {
+                return None;
+            }
+
+            // The PCMPxSTRx instructions always read 16 bytes worth of
+            // data. To avoid walking off the end of a page (and
+            // potentially into a protected area), we read in 16-byte
+            // chunks aligned to the *end* of the byte slice. The
+            // instructions handle truly unaligned access just fine; the
+            // trick lies in searching the leftover bytes at the beginning
+            // of the byte slice.
+
+            let true_ptr = haystack.as_ptr();
+
+            // Start at the 16-byte-aligned block *before* the byte slice
+            // starts
+            let ptr = (true_ptr as usize & !0xF) as *const u8;
+
+            // Find where the byte slice really starts
+            let initial_offset = true_ptr as usize & 0xF;
+            let mut offset = initial_offset;
+
+            // If the byte slice is magically aligned, skip this extra work
+            if offset != 0 {
+                match self.initial_unaligned_byte_slice(ptr, offset, len) {
+                    InitialMatch::Complete(result) => return result,
+                    InitialMatch::Incomplete(length_of_leading_slice) => {
+                        offset = 16;
+                        len -= length_of_leading_slice;
+                    }
+                }
+            }
+
+            unsafe { self.find_in_aligned_blocks(ptr, offset, len) }
+                .map(|idx| idx - initial_offset)
+        }
+
+        /// Like `find`, but skips the work for leading bytes that are
+        /// not 16-byte-aligned.
+        ///
+        /// ### Safety
+        ///
+        /// `haystack` must start on a 16-byte boundary. Otherwise the
+        /// last read may run off the end of the byte slice.
+        #[inline]
+        #[must_use]
+        pub unsafe fn find_aligned(&self, haystack: &[u8]) -> Option<usize> {
+            debug_assert_eq!(0, haystack.as_ptr() as usize & 0xF, "haystack is not 16-byte-aligned");
+            self.find_in_aligned_blocks(haystack.as_ptr(), 0, haystack.len())
+        }
+
+        /// Find the index of the first match from `ptr`, starting
+        /// `offset` bytes in. `ptr + offset` must be 16-byte-aligned.
+        #[inline]
+        unsafe fn find_in_aligned_blocks(&self,
+                                         ptr: *const u8,
+                                         mut offset: usize,
+                                         mut len: usize)
+                                         -> Option<usize> {
+            while len != 0 {
+                let res: u32;
+
+                res = self.operation.body(ptr, offset, len);
+
+                // We know if it matched if the zero flag is set (or
+                // unset?), we shouldn't need to test res...
+                //
+                // `res` only indexes into this window; widen it before
+                // adding the offset so haystacks past 4 GiB work.
+                if res == 16 {
+                    offset += 16;
+                    len = len.saturating_sub(16);
+                } else {
+                    return Some(res as usize + offset);
+                }
+            }
+
+            None
+        }
+
+        #[inline]
+        fn initial_unaligned_byte_slice(&self,
+                                        ptr: *const u8,
+                                        offset: usize,
+                                        len: usize)
+                                        -> InitialMatch {
+            // We use the PCMPESTRM instruction on the 16-byte-aligned
+            // block that contains the *start* of the byte slice. This
+            // returns a mask of all the matching bytes. We can can then
+            // ignore unrelated leading bits to find the index of the
+            // first related byte (if any).
+
+            let mut matching_bytes = unsafe { self.operation.initial(ptr, offset, len) };
+
+            // Ignore matches that occurred before our byte slice began
+            matching_bytes >>= offset;
+
+            if matching_bytes != 0 {
+                // Matched somewhere in there, find the least significant bit
+                let index = matching_bytes.trailing_zeros() as usize;
+                return InitialMatch::Complete(Some(index));
+            }
+
+            let length_of_leading_slice = 16 - offset;
+
+            if len < length_of_leading_slice {
+                // We've searched the entire byte slice
+                InitialMatch::Complete(None)
+            } else {
+                InitialMatch::Incomplete(length_of_leading_slice)
+            }
         }
 
-        // The PCMPxSTRx instructions always read 16 bytes worth of
-        // data. To avoid walking off the end of a page (and
-        // potentially into a protected area), we read in 16-byte
-        // chunks aligned to the *end* of the byte slice. The
-        // instructions handle truly unaligned access just fine; the
-        // trick lies in searching the leftover bytes at the beginning
-        // of the byte slice.
+        /// Find the index of the last match.
+        #[inline]
+        #[must_use]
+        pub fn rfind(&self, haystack: &[u8]) -> Option<usize> {
+            let len = haystack.len();
+
+            if len == 0 {
+                return None;
+            }
+
+            // Walk the 16-byte-aligned blocks backwards, starting with
+            // the block that contains the *last* byte of the byte slice.
 
-        let true_ptr = haystack.as_ptr();
+            let start = haystack.as_ptr() as usize;
+            let end = start + len;
 
-        // Start at the 16-byte-aligned block *before* the byte slice
-        // starts
-        let ptr = (true_ptr as usize & !0xF) as *const u8;
+            let mut block = (end - 1) & !0xF;
 
-        // Find where the byte slice really starts
-        let initial_offset = true_ptr as usize & 0xF;
-        let mut offset = initial_offset;
+            loop {
+                let matching_bytes = self.aligned_block_matches(block, start, end);
+
+                if matching_bytes != 0 {
+                    // Matched somewhere in there, find the most significant bit
+                    let index = 63 - matching_bytes.leading_zeros() as usize;
+                    return Some(block + index - start);
+                }
 
-        // If the byte slice is magically aligned, skip this extra work
-        if offset != 0 {
-            match self.initial_unaligned_byte_slice(ptr, offset, len) {
-                InitialMatch::Complete(result) => return result,
-                InitialMatch::Incomplete(length_of_leading_slice) => {
-                    offset = 16;
-                    len -= length_of_leading_slice;
+                if block <= start {
+                    // We've searched the entire byte slice
+                    return None;
                 }
+
+                block -= 16;
             }
         }
 
-        while len != 0 {
-            let res: u32;
+        /// Count the bytes that match.
+        #[inline]
+        #[must_use]
+        pub fn count(&self, haystack: &[u8]) -> usize {
+            let len = haystack.len();
 
-            res = unsafe { self.operation.body(ptr, offset, len) };
+            if len == 0 {
+                return 0;
+            }
 
-            // We know if it matched if the zero flag is set (or
-            // unset?), we shouldn't need to test res...
-            if res == 16 {
-                offset += 16;
-                len = len.saturating_sub(16);
-            } else {
-                return Some(res as usize + offset - initial_offset);
+            // Walk every 16-byte-aligned block that overlaps the byte
+            // slice and count the bits set in each mask.
+
+            let start = haystack.as_ptr() as usize;
+            let end = start + len;
+
+            let mut block = start & !0xF;
+            let mut count = 0;
+
+            while block < end {
+                let matching_bytes = self.aligned_block_matches(block, start, end);
+                count += matching_bytes.count_ones() as usize;
+                block += 16;
+            }
+
+            count
+        }
+
+        /// Whether any byte matches.
+        #[inline]
+        #[must_use]
+        pub fn contains(&self, haystack: &[u8]) -> bool {
+            let len = haystack.len();
+
+            if len == 0 {
+                return false;
+            }
+
+            // We only care if *any* block has a match, so there's no
+            // need to find the index of the matching byte.
+
+            let start = haystack.as_ptr() as usize;
+            let end = start + len;
+
+            let mut block = start & !0xF;
+
+            while block < end {
+                if self.aligned_block_matches(block, start, end) != 0 {
+                    return true;
+                }
+                block += 16;
             }
+
+            false
         }
 
-        None
+        /// Find the index of the first byte that does not match.
+        #[inline]
+        #[must_use]
+        pub fn find_not(&self, haystack: &[u8]) -> Option<usize> {
+            let len = haystack.len();
+
+            if len == 0 {
+                return None;
+            }
+
+            // Walk the 16-byte-aligned blocks forwards, looking for a
+            // byte of the byte slice that is missing from the mask.
+
+            let start = haystack.as_ptr() as usize;
+            let end = start + len;
+
+            let mut block = start & !0xF;
+
+            while block < end {
+                let matching_bytes = self.aligned_block_matches(block, start, end);
+                let other_bytes = !matching_bytes & aligned_block_bytes(block, start, end);
+
+                if other_bytes != 0 {
+                    let index = other_bytes.trailing_zeros() as usize;
+                    return Some(block + index - start);
+                }
+
+                block += 16;
+            }
+
+            None
+        }
+
+        /// Find the index of the last byte that does not match.
+        #[inline]
+        #[must_use]
+        pub fn rfind_not(&self, haystack: &[u8]) -> Option<usize> {
+            let len = haystack.len();
+
+            if len == 0 {
+                return None;
+            }
+
+            // Walk the 16-byte-aligned blocks backwards, looking for a
+            // byte of the byte slice that is missing from the mask.
+
+            let start = haystack.as_ptr() as usize;
+            let end = start + len;
+
+            let mut block = (end - 1) & !0xF;
+
+            loop {
+                let matching_bytes = self.aligned_block_matches(block, start, end);
+                let other_bytes = !matching_bytes & aligned_block_bytes(block, start, end);
+
+                if other_bytes != 0 {
+                    let index = 63 - other_bytes.leading_zeros() as usize;
+                    return Some(block + index - start);
+                }
+
+                if block <= start {
+                    return None;
+                }
+
+                block -= 16;
+            }
+        }
+
+        #[inline]
+        fn aligned_block_matches(&self, block: usize, start: usize, end: usize) -> u64 {
+            // Aligned blocks never cross a page boundary, so it's safe to
+            // read all 16 bytes of a block even when the byte slice only
+            // covers part of it. We use the PCMPESTRM instruction to get
+            // a mask of all the matching bytes and then ignore any bits
+            // that fall outside of the byte slice.
+
+            let matching_bytes = unsafe { self.operation.initial(block as *const u8, 0, 16) };
+            matching_bytes & aligned_block_bytes(block, start, end)
+        }
     }
 
+    /// A mask of the bytes of the 16-byte-aligned `block` that fall
+    /// within the byte slice from `start` to `end`.
     #[inline]
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn initial_unaligned_byte_slice(&self,
-                                    ptr: *const u8,
-                                    offset: usize,
-                                    len: usize)
-                                    -> InitialMatch {
-        // We use the PCMPESTRM instruction on the 16-byte-aligned
-        // block that contains the *start* of the byte slice. This
-        // returns a mask of all the matching bytes. We can can then
-        // ignore unrelated leading bits to find the index of the
-        // first related byte (if any).
-
-        let mut matching_bytes = unsafe { self.operation.initial(ptr, offset, len) };
-
-        // Ignore matches that occurred before our byte slice began
-        matching_bytes >>= offset;
+    fn aligned_block_bytes(block: usize, start: usize, end: usize) -> u64 {
+        let mut bytes = 0xFFFF;
 
-        if matching_bytes != 0 {
-            // Matched somewhere in there, find the least significant bit
-            let index = matching_bytes.trailing_zeros() as usize;
-            return InitialMatch::Complete(Some(index));
+        // Ignore bytes after our byte slice ended
+        if end - block < 16 {
+            bytes &= (1 << (end - block)) - 1;
+        }
+
+        // Ignore bytes before our byte slice began
+        if block < start {
+            bytes &= !((1 << (start - block)) - 1);
         }
 
-        let length_of_leading_slice = 16 - offset;
+        bytes
+    }
+}
 
-        if len < length_of_leading_slice {
-            // We've searched the entire byte slice
-            InitialMatch::Complete(None)
-        } else {
-            InitialMatch::Incomplete(length_of_leading_slice)
+#[cfg(target_arch = "x86_64")]
+use packed::{PackedCompareOperation, UnalignedByteSliceHandler};
+
+/// The pieces the searchers in this crate are built from, for writing
+/// searchers around other PCMPxSTRx comparisons. These are only
+/// available with the `unstable` feature and may change in any
+/// release.
+///
+/// ```
+/// use std::arch::x86_64::*;
+/// use std::cmp::min;
+/// use jetscii::experimental::{PackedCompareOperation, UnalignedByteSliceHandler};
+///
+/// /// Bytes that are not ASCII digits
+/// struct NotDigits;
+///
+/// const NOT_IN_RANGES: i32 = _SIDD_UBYTE_OPS | _SIDD_CMP_RANGES | _SIDD_MASKED_NEGATIVE_POLARITY;
+///
+/// impl PackedCompareOperation for NotDigits {
+///     #[target_feature(enable = "sse4.2")]
+///     unsafe fn initial(&self, ptr: *const u8, offset: usize, len: usize) -> u64 {
+///         let needle = _mm_set_epi64x(0, 0x3930);
+///         let haystack = _mm_loadu_si128(ptr as *const __m128i);
+///         let mask = _mm_cmpestrm::<NOT_IN_RANGES>(needle, 2, haystack,
+///                                                  min(offset + len, 16) as i32);
+///         _mm_cvtsi128_si64(mask) as u64
+///     }
+///
+///     #[target_feature(enable = "sse4.2")]
+///     unsafe fn body(&self, ptr: *const u8, offset: usize, len: usize) -> u32 {
+///         let needle = _mm_set_epi64x(0, 0x3930);
+///         let haystack = _mm_loadu_si128(ptr.offset(offset as isize) as *const __m128i);
+///         _mm_cmpestri::<NOT_IN_RANGES>(needle, 2, haystack, min(len, 16) as i32) as u32
+///     }
+/// }
+///
+/// if is_x86_feature_detected!("sse4.2") {
+///     let handler = unsafe { UnalignedByteSliceHandler::new(NotDigits) };
+///     assert_eq!(Some(19), handler.find(b"0123456789012345678-0123"));
+///     assert_eq!(Some(19), handler.rfind(b"0123456789012345678-0123"));
+///     assert_eq!(None, handler.find(b"4815162342"));
+/// }
+/// ```
+#[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+pub mod experimental {
+    pub use packed::{PackedCompareOperation, UnalignedByteSliceHandler};
+}
+
+/// Whether the PCMPxSTRx instructions may be used. Enabling the
+/// `unstable` feature asserts that they are always available.
+#[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+#[inline]
+fn has_sse42() -> bool {
+    true
+}
+
+/// Whether the PCMPxSTRx instructions may be used.
+#[cfg(all(not(feature = "unstable"), feature = "std", target_arch = "x86_64"))]
+#[inline]
+fn has_sse42() -> bool {
+    static SSE42: AtomicU8 = AtomicU8::new(UNKNOWN);
+    detect_once(&SSE42, || is_x86_feature_detected!("sse4.2"))
+}
+
+/// Whether the PCMPxSTRx instructions may be used. Without `std`
+/// the CPU can't be asked, so only the target features the crate was
+/// compiled with are used.
+#[cfg(all(not(feature = "unstable"), not(feature = "std"), target_arch = "x86_64"))]
+#[inline]
+fn has_sse42() -> bool {
+    cfg!(target_feature = "sse4.2")
+}
+
+/// Whether the 32-byte AVX2 instructions may be used.
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+#[inline]
+fn has_avx2() -> bool {
+    static AVX2: AtomicU8 = AtomicU8::new(UNKNOWN);
+    detect_once(&AVX2, || is_x86_feature_detected!("avx2"))
+}
+
+/// Whether the 32-byte AVX2 instructions may be used. Without `std`
+/// only the target features the crate was compiled with are used.
+#[cfg(all(not(feature = "std"), target_arch = "x86_64"))]
+#[inline]
+fn has_avx2() -> bool {
+    cfg!(target_feature = "avx2")
+}
+
+/// Whether the 64-byte AVX-512 byte instructions may be used. They
+/// can lower the clock speed of the whole core, so they are only
+/// considered when the `avx512` feature is enabled.
+#[cfg(all(feature = "avx512", feature = "std", target_arch = "x86_64"))]
+#[inline]
+fn has_avx512bw() -> bool {
+    static AVX512BW: AtomicU8 = AtomicU8::new(UNKNOWN);
+    detect_once(&AVX512BW, || is_x86_feature_detected!("avx512bw"))
+}
+
+/// Whether the 64-byte AVX-512 byte instructions may be used. Without
+/// `std` only the target features the crate was compiled with are
+/// used.
+#[cfg(all(feature = "avx512", not(feature = "std"), target_arch = "x86_64"))]
+#[inline]
+fn has_avx512bw() -> bool {
+    cfg!(target_feature = "avx512bw")
+}
+
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+const UNKNOWN: u8 = 0;
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+const ABSENT: u8 = 1;
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+const PRESENT: u8 = 2;
+
+/// The CPU is only asked once; the answer is cached for every later
+/// search.
+#[cfg(all(feature = "std", target_arch = "x86_64"))]
+#[inline]
+fn detect_once(cache: &AtomicU8, detect: fn() -> bool) -> bool {
+    match cache.load(Ordering::Relaxed) {
+        UNKNOWN => {
+            let present = detect();
+            cache.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+            present
+        }
+        state => state == PRESENT,
+    }
+}
+
+/// Ask the CPU to start loading the `len` bytes from `ptr` into the
+/// cache. Prefetching never faults, so the bytes don't have to be
+/// part of any allocation.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+fn prefetch(ptr: *const u8, len: usize) {
+    use core::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+    for line in (0..len).step_by(64) {
+        unsafe { _mm_prefetch::<_MM_HINT_T0>(ptr.wrapping_add(line) as *const i8) }
+    }
+}
+
+/// Only x86_64 has a prefetch instruction that we use.
+#[cfg(not(target_arch = "x86_64"))]
+#[inline]
+fn prefetch(_ptr: *const u8, _len: usize) {}
+
+/// Find the first occurrence of `needle` in the 8-byte words at the
+/// start of `haystack`. Returns how many bytes were searched along
+/// with the index of the match, if any; leftover bytes that don't
+/// fill a word are not searched.
+#[cfg(not(any(target_arch = "aarch64",
+              all(target_arch = "wasm32", target_feature = "simd128"))))]
+fn find_byte_swar(needle: u8, haystack: &[u8]) -> (usize, Option<usize>) {
+    const LO_BITS: u64 = 0x0101010101010101;
+    const HI_BITS: u64 = 0x8080808080808080;
+
+    let pattern = LO_BITS * needle as u64;
+    let mut offset = 0;
+
+    while haystack.len() - offset >= 8 {
+        let mut word = [0; 8];
+        word.copy_from_slice(&haystack[offset..offset + 8]);
+
+        // Bytes equal to the needle become zero. Subtracting one
+        // from every byte only sets the high bit of bytes that were
+        // zero (or that borrowed from a zero byte below them, which
+        // can't come before the first real match).
+        let mismatches = u64::from_le_bytes(word) ^ pattern;
+        let matching_bytes = mismatches.wrapping_sub(LO_BITS) & !mismatches & HI_BITS;
+
+        if matching_bytes != 0 {
+            let index = matching_bytes.trailing_zeros() as usize / 8;
+            return (offset + 8, Some(offset + index));
         }
+
+        offset += 8;
     }
+
+    (offset, None)
 }
 
 #[doc(hidden)]
 #[macro_export]
 macro_rules! ascii_chars_inner {
-    ($cnt:expr,
-     $c0:expr, $c1:expr, $c2:expr, $c3:expr,
-     $c4:expr, $c5:expr, $c6:expr, $c7:expr) => ({
-         let lo =
-             ($c0 as u64) <<  0 | ($c1 as u64) <<  8 | ($c2 as u64) << 16 | ($c3 as u64) << 24 |
-             ($c4 as u64) << 32 | ($c5 as u64) << 40 | ($c6 as u64) << 48 | ($c7 as u64) << 56;
-         let ac = $crate::AsciiChars::from_words(lo, 0, $cnt);
-         ac.with_fallback(move |c| {
-             c == $c0 as u8 || c == $c1 as u8 || c == $c2 as u8 || c == $c3 as u8 ||
-             c == $c4 as u8 || c == $c5 as u8 || c == $c6 as u8 || c == $c7 as u8
-         })
-     });
-    ($cnt:expr,
-     $c0:expr,   $c1:expr,  $c2:expr,  $c3:expr,
-     $c4:expr,   $c5:expr,  $c6:expr,  $c7:expr,
-     $c8:expr,   $c9:expr, $c10:expr, $c11:expr,
-     $c12:expr, $c13:expr, $c14:expr, $c15:expr) => ({
-         let lo =
-             ($c0 as u64) <<  0 | ($c1 as u64) <<  8 | ($c2 as u64) << 16 | ($c3 as u64) << 24 |
-             ($c4 as u64) << 32 | ($c5 as u64) << 40 | ($c6 as u64) << 48 | ($c7 as u64) << 56;
-         let hi =
-             ( $c8 as u64) <<  0 | ( $c9 as u64) <<  8 | ($c10 as u64) << 16 | ($c11 as u64) << 24 |
-             ($c12 as u64) << 32 | ($c13 as u64) << 40 | ($c14 as u64) << 48 | ($c15 as u64) << 56;
-         let ac = $crate::AsciiChars::from_words(lo, hi, $cnt);
-         ac.with_fallback(move |c| {
-             c ==  $c0 as u8 || c ==  $c1 as u8 || c ==  $c2 as u8 || c ==  $c3 as u8 ||
-             c ==  $c4 as u8 || c ==  $c5 as u8 || c ==  $c6 as u8 || c ==  $c7 as u8 ||
-             c ==  $c8 as u8 || c ==  $c9 as u8 || c == $c10 as u8 || c == $c11 as u8 ||
-             c == $c12 as u8 || c == $c13 as u8 || c == $c14 as u8 || c == $c15 as u8
-         })
-     });
+    // Sort each argument into a range of characters or a single one,
+    // trying ranges first as they would also parse as expressions.
+    // Literals are kept apart from other expressions so that they
+    // can be checked at compile time.
+    (@munch $with:ident [$($item:tt)*] $lo:literal ..= $hi:literal $(, $($rest:tt)*)?) =>
+        (ascii_chars_inner!(@munch $with [$($item)* (range $lo, $hi)] $($($rest)*)?));
+    (@munch $with:ident [$($item:tt)*] $c:literal $(, $($rest:tt)*)?) =>
+        (ascii_chars_inner!(@munch $with [$($item)* (literal $c)] $($($rest)*)?));
+    (@munch $with:ident [$($item:tt)*] $c:expr $(, $($rest:tt)*)?) =>
+        (ascii_chars_inner!(@munch $with [$($item)* (char $c)] $($($rest)*)?));
+    (@munch fallback [$($item:tt)*]) => ({
+        let chars = ascii_chars_inner!(@munch chars [$($item)*]);
+        chars.with_fallback(move |c| false $(|| ascii_chars_inner!(@matches c, $item))*)
+    });
+    (@munch chars [$($item:tt)*]) => ({
+        // Fails to compile when there are too many characters
+        const _AT_MOST_16_CHARACTERS: usize = 16 - (0 $(+ ascii_chars_inner!(@len $item))*);
+        let _ = _AT_MOST_16_CHARACTERS;
+        $(ascii_chars_inner!(@check $item);)*
+
+        let chars = $crate::AsciiChars::new();
+        $(let chars = ascii_chars_inner!(@push chars, $item);)*
+        chars
+    });
+
+    (@check (range $lo:literal, $hi:literal)) => ({
+        ascii_chars_inner!(@check (literal $lo));
+        ascii_chars_inner!(@check (literal $hi));
+    });
+    (@check (literal $c:literal)) => {
+        // Fails to compile when the literal isn't ASCII
+        const _: () = assert!(($c as u32) < 128, "{}",
+                              concat!("AsciiChars can only search for ASCII characters, not ",
+                                      stringify!($c)));
+    };
+    (@check (char $c:expr)) => (());
+
+    (@len (range $lo:literal, $hi:literal)) => ($hi as usize + 1 - $lo as usize);
+    (@len (literal $c:literal)) => (1);
+    (@len (char $c:expr)) => (1);
+
+    (@push $chars:ident, (range $lo:literal, $hi:literal)) =>
+        ($chars.__macro_push_range($lo as u32, $hi as u32));
+    (@push $chars:ident, (literal $c:literal)) =>
+        ($chars.__macro_push($c as u32));
+    (@push $chars:ident, (char $c:expr)) =>
+        ($chars.__macro_push($c as u32));
+
+    (@matches $b:ident, (range $lo:literal, $hi:literal)) =>
+        ($lo as u8 <= $b && $b <= $hi as u8);
+    (@matches $b:ident, (literal $c:literal)) =>
+        ($b == $c as u8);
+    (@matches $b:ident, (char $c:expr)) =>
+        ($b == $c as u8);
 }
 
 /// A convenience constructor for an AsciiChars that automatically
-/// implements a fallback. Provide 1 to 16 characters.
-// Pad out undefined variables with duplicate values, hope that the
-// optimizer deduplicates them.
+/// implements a fallback. Provide 1 to 16 characters, either as
+/// `char`s or bytes, or as inclusive ranges of literals; having more
+/// than 16 is a compile error.
+///
+/// ```
+/// #[macro_use]
+/// extern crate jetscii;
+///
+/// fn main() {
+///     let hex = ascii_chars!('0'..='9', b'A'..=b'F');
+///     assert_eq!(Some(7), "color: FA0".find(hex));
+/// }
+/// ```
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate jetscii;
+///
+/// fn main() {
+///     let too_many = ascii_chars!('-', 'a'..='p');
+/// }
+/// ```
+///
+/// A literal that isn't ASCII is also a compile error, however it is
+/// written.
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate jetscii;
+///
+/// fn main() {
+///     let accented = ascii_chars!('\u{e9}', '<');
+/// }
+/// ```
 #[macro_export]
 macro_rules! ascii_chars {
-    ($c0:expr) =>
-        (ascii_chars_inner!(1, $c0, $c0, $c0, $c0, $c0, $c0, $c0, $c0));
-    ($c0:expr, $c1:expr) =>
-        (ascii_chars_inner!(2, $c0, $c1, $c0, $c0, $c0, $c0, $c0, $c0));
-    ($c0:expr, $c1:expr, $c2:expr) =>
-        (ascii_chars_inner!(3, $c0, $c1, $c2, $c0, $c0, $c0, $c0, $c0));
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr) =>
-        (ascii_chars_inner!(4, $c0, $c1, $c2, $c3, $c0, $c0, $c0, $c0));
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr, $c4:expr) =>
-        (ascii_chars_inner!(5, $c0, $c1, $c2, $c3, $c4, $c0, $c0, $c0));
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr, $c4:expr, $c5:expr) =>
-        (ascii_chars_inner!(6, $c0, $c1, $c2, $c3, $c4, $c5, $c0, $c0));
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr, $c4:expr, $c5:expr, $c6:expr) =>
-        (ascii_chars_inner!(7, $c0, $c1, $c2, $c3, $c4, $c5, $c6, $c0));
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr, $c4:expr, $c5:expr, $c6:expr, $c7:expr) =>
-        (ascii_chars_inner!(8, $c0, $c1, $c2, $c3, $c4, $c5, $c6, $c7));
-
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr, $c4:expr, $c5:expr, $c6:expr, $c7:expr,
-     $c8:expr) =>
-        (ascii_chars_inner!(9, $c0, $c1, $c2, $c3, $c4, $c5, $c6, $c7,
-                       $c8, $c0, $c0, $c0, $c0, $c0, $c0, $c0));
-
-    ($c0:expr, $c1:expr, $c2:expr, $c3:expr, $c4:expr, $c5:expr, $c6:expr, $c7:expr,
-     $c8:expr, $c9:expr) =>
-        (ascii_chars_inner!(10, $c0, $c1, $c2, $c3, $c4, $c5, $c6, $c7,
-                        $c8, $c9, $c0, $c0, $c0, $c0, $c0, $c0));
-
-    ($c0:expr, $c1:expr,  $c2:expr, $c3:expr, $c4:expr, $c5:expr, $c6:expr, $c7:expr,
-     $c8:expr, $c9:expr, $c10:expr) =>
-        (ascii_chars_inner!(11, $c0, $c1,  $c2, $c3, $c4, $c5, $c6,$c7,
-                        $c8, $c9, $c10, $c0, $c0, $c0, $c0, $c0));
-
-    ($c0:expr, $c1:expr,  $c2:expr,  $c3:expr, $c4:expr, $c5:expr, $c6:expr, $c7:expr,
-     $c8:expr, $c9:expr, $c10:expr, $c11:expr) =>
-        (ascii_chars_inner!(12, $c0, $c1,  $c2,  $c3, $c4, $c5, $c6, $c7,
-                        $c8, $c9, $c10, $c11, $c0, $c0, $c0, $c0));
-
-    ($c0:expr, $c1:expr,  $c2:expr,  $c3:expr,  $c4:expr, $c5:expr, $c6:expr, $c7:expr,
-     $c8:expr, $c9:expr, $c10:expr, $c11:expr, $c12:expr) =>
-        (ascii_chars_inner!(13, $c0, $c1,  $c2,  $c3,  $c4, $c5, $c6, $c7,
-                        $c8, $c9, $c10, $c11, $c12, $c0, $c0, $c0));
-
-    ($c0:expr, $c1:expr,  $c2:expr,  $c3:expr,  $c4:expr,  $c5:expr, $c6:expr, $c7:expr,
-     $c8:expr, $c9:expr, $c10:expr, $c11:expr, $c12:expr, $c13:expr) =>
-        (ascii_chars_inner!(14, $c0, $c1,  $c2,  $c3,  $c4,  $c5, $c6, $c7,
-                        $c8, $c9, $c10, $c11, $c12, $c13, $c0, $c0));
-
-    ($c0:expr, $c1:expr,  $c2:expr,  $c3:expr,  $c4:expr,  $c5:expr,  $c6:expr, $c7:expr,
-     $c8:expr, $c9:expr, $c10:expr, $c11:expr, $c12:expr, $c13:expr, $c14:expr) =>
-        (ascii_chars_inner!(15, $c0, $c1,  $c2,  $c3,  $c4,  $c5,  $c6, $c7,
-                        $c8, $c9, $c10, $c11, $c12, $c13, $c14, $c0));
-
-    ($c0:expr, $c1:expr,  $c2:expr,  $c3:expr,  $c4:expr,  $c5:expr,  $c6:expr,  $c7:expr,
-     $c8:expr, $c9:expr, $c10:expr, $c11:expr, $c12:expr, $c13:expr, $c14:expr, $c15:expr) =>
-        (ascii_chars_inner!(16, $c0, $c1,  $c2,  $c3,  $c4,  $c5,  $c6,  $c7,
-                        $c8, $c9, $c10, $c11, $c12, $c13, $c14, $c15));
+    ($($arg:tt)+) => (ascii_chars_inner!(@munch fallback [] $($arg)+));
+}
+
+/// Like `ascii_chars!`, but builds just the AsciiChars, which uses its
+/// generated fallback. With constant arguments it can be used in
+/// `const` and `static` items.
+///
+/// ```
+/// #[macro_use]
+/// extern crate jetscii;
+///
+/// use jetscii::AsciiChars;
+///
+/// const DELIMITERS: AsciiChars = const_ascii_chars!(b'<', b'>', b'&', b'0'..=b'9');
+///
+/// fn main() {
+///     assert_eq!(Some(2), "ab&cd".find(DELIMITERS));
+/// }
+/// ```
+#[macro_export]
+macro_rules! const_ascii_chars {
+    ($($arg:tt)+) => (ascii_chars_inner!(@munch chars [] $($arg)+));
 }
 
 const MAXBYTES: u8 = 16;
@@ -252,7 +760,7 @@ const ASCII_WORD_MASK: u64 = 0x7f7f7f7f7f7f7f7f;
 
 /// Searches a string for a set of ASCII characters. Up to 16
 /// characters may be used.
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,Default)]
 pub struct AsciiChars {
     needle: u64,
     needle_hi: u64,
@@ -282,374 +790,5856 @@ impl AsciiChars {
         }
     }
 
-    /// Add a new ASCII character to the set to search for.
+    /// Create an AsciiChars that searches for each of `bytes`.
+    ///
+    /// ### Panics
+    ///
+    /// - If there are more than 16 bytes.
+    /// - If any byte is non-ASCII.
+    pub fn from_bytes(bytes: &[u8]) -> AsciiChars {
+        match AsciiChars::try_from_bytes(bytes) {
+            Ok(chars) => chars,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Create an AsciiChars that searches for each of `bytes`, or an
+    /// error if there are more than 16 bytes.
+    ///
+    /// ### Panics
+    ///
+    /// - If any byte is non-ASCII.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<AsciiChars, TooManyBytes> {
+        if bytes.len() > MAXBYTES as usize {
+            return Err(TooManyBytes { len: bytes.len() });
+        }
+
+        let mut words = [0u64; 2];
+        for (index, &byte) in bytes.iter().enumerate() {
+            assert!(byte < 128, "AsciiChars can only search for ASCII bytes, not 0x{:02x}", byte);
+            words[index / 8] |= (byte as u64) << (8 * (index % 8));
+        }
+
+        Ok(AsciiChars::from_words(words[0], words[1], bytes.len()))
+    }
+
+    /// Create an AsciiChars that searches for each distinct byte of
+    /// `bytes`, in the order they first appear. Repeated bytes don't
+    /// use up any of the 16 places.
+    ///
+    /// ### Panics
+    ///
+    /// - If there are more than 16 distinct bytes.
+    /// - If any byte is non-ASCII.
+    pub fn from_bytes_dedup(bytes: &[u8]) -> AsciiChars {
+        let mut chars = AsciiChars::new();
+        for &byte in bytes {
+            chars.push_unique(byte);
+        }
+        chars
+    }
+
+    /// Create an AsciiChars that searches for each of `chars`, or an
+    /// error if any of them is not ASCII.
+    ///
+    /// ### Panics
+    ///
+    /// - If there are more than 16 characters.
+    pub fn try_from_chars(chars: &[char]) -> Result<AsciiChars, NotAsciiError> {
+        let mut set = AsciiChars::new();
+        for &c in chars {
+            set.try_push(c)?;
+        }
+        Ok(set)
+    }
+
+    /// Add a new ASCII character to the end of the set to search for.
+    ///
+    /// Every byte must be ASCII, as a non-ASCII byte would match
+    /// pieces of multi-byte UTF-8 characters. Use `try_push` to add a
+    /// `char` that has not been checked yet.
     ///
     /// ### Panics
     ///
     /// - If you add more than 16 characters.
     /// - If you add a non-ASCII byte.
     pub fn push(&mut self, byte: u8) {
-        assert!(byte < 128);
-        assert!(self.count < MAXBYTES);
-        self.needle_hi <<= 8;
-        self.needle_hi |= self.needle >> (64 - 8);
-        self.needle <<= 8;
-        self.needle |= byte as u64;
-        self.count += 1;
+        assert!(byte < 128, "AsciiChars can only search for ASCII bytes, not 0x{:02x}", byte);
+        assert!(self.count < MAXBYTES, "AsciiChars can search for at most {} bytes", MAXBYTES);
+        *self = self.pushed(byte);
     }
 
-    /// Builds a searcher with a fallback implementation for when the
-    /// optimized version is not available. The fallback should search
-    /// for the **exact** same set of characters.
-    pub fn with_fallback<F>(self, fallback: F) -> AsciiCharsWithFallback<F>
-        where F: Fn(u8) -> bool
-    {
-        AsciiCharsWithFallback {
-            inner: self,
-            fallback: fallback,
+    /// Like `push`, but only adds `byte` if it isn't in the set
+    /// already. Returns whether it was added.
+    ///
+    /// ### Panics
+    ///
+    /// - If `byte` is new and the set already has 16 characters.
+    /// - If `byte` is non-ASCII.
+    pub fn push_unique(&mut self, byte: u8) -> bool {
+        if self.contains_byte(byte) {
+            return false;
         }
+        self.push(byte);
+        true
     }
 
-    /// Find the index of the first character in the set.
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    /// Like `push`, but returns the set with `byte` added to the end
+    /// instead of changing it in place, so that sets can be built up
+    /// in a `const`.
+    ///
+    /// ```
+    /// use jetscii::AsciiChars;
+    ///
+    /// const XML: AsciiChars = AsciiChars::new().const_push(b'<').const_push(b'>').const_push(b'&');
+    ///
+    /// assert_eq!(Some(3), "abc&".find(XML));
+    /// ```
+    ///
+    /// The set it is called on isn't changed, so ignoring the result
+    /// is a warning:
+    ///
+    /// ```compile_fail
+    /// #![deny(unused_must_use)]
+    ///
+    /// use jetscii::AsciiChars;
+    ///
+    /// fn main() {
+    ///     let xml = AsciiChars::new();
+    ///     xml.const_push(b'<');
+    /// }
+    /// ```
+    ///
+    /// ### Panics
+    ///
+    /// These are compile errors in a `const`.
+    ///
+    /// - If you add more than 16 characters.
+    /// - If you add a non-ASCII byte.
     #[inline]
-    pub fn find(self, haystack: &str) -> Option<usize> {
-        UnalignedByteSliceHandler { operation: self }.find(haystack.as_bytes())
+    #[must_use]
+    pub const fn const_push(self, byte: u8) -> AsciiChars {
+        assert!(byte < 128, "AsciiChars can only search for ASCII bytes");
+        assert!(self.count < MAXBYTES, "AsciiChars can search for at most 16 bytes");
+        self.pushed(byte)
     }
-}
 
-impl fmt::Debug for AsciiChars {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,
-               "AsciiChars {{ lo: 0x{:016x}, hi: 0x{:016x}, count: {} }}",
-               self.needle,
-               self.needle_hi,
-               self.count)
+    /// The set with `byte` added to the end, replacing whatever unused
+    /// byte was there.
+    #[inline]
+    const fn pushed(self, byte: u8) -> AsciiChars {
+        let index = self.count as usize;
+        let shift = 8 * (index % 8);
+        let mut chars = self;
+        if index < 8 {
+            chars.needle = chars.needle & !(0xff << shift) | (byte as u64) << shift;
+        } else {
+            chars.needle_hi = chars.needle_hi & !(0xff << shift) | (byte as u64) << shift;
+        }
+        chars.count += 1;
+        chars
     }
-}
 
-#[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-impl PackedCompareOperation for AsciiChars {
-    unsafe fn initial(&self, ptr: *const u8, offset: usize, len: usize) -> u64 {
-        let matching_bytes;
-
-        asm!("movlhps $2, $1
-              pcmpestrm $$0, ($3), $1"
-             : // output operands
-             "={xmm0}"(matching_bytes)
-             : // input operands
-             "x"(self.needle),
-             "x"(self.needle_hi),
-             "r"(ptr),
-             "{rdx}"(offset + len), // saturates at 16
-             "{rax}"(self.count as u64)
-             : // clobbers
-             "cc"
-             : // options
-        );
-
-        matching_bytes
+    /// `const_push` for `ascii_chars!`. Takes a `u32` to catch `char`s
+    /// that aren't ASCII before they are truncated to a byte.
+    #[doc(hidden)]
+    #[inline]
+    pub const fn __macro_push(self, c: u32) -> AsciiChars {
+        assert!(c < 128, "AsciiChars can only search for ASCII characters");
+        self.const_push(c as u8)
     }
 
-    unsafe fn body(&self, ptr: *const u8, offset: usize, len: usize) -> u32 {
-        let res;
-
-        asm!("# Move low word of $2 to high word of $1
-              movlhps $2, $1
-              pcmpestri $$0, ($3, $4), $1"
-             : // output operands
-             "={ecx}"(res)
-             : // input operands
-             "x"(self.needle),
-             "x"(self.needle_hi),
-             "r"(ptr),
-             "r"(offset)
-             "{rdx}"(len),              // haystack length
-             "{rax}"(self.count as u64) // needle length
-             : // clobbers
-             "cc"
-             : // options
-         );
-
-        res
+    /// Push each character from `lo` to `hi` inclusive.
+    #[doc(hidden)]
+    #[inline]
+    pub const fn __macro_push_range(self, lo: u32, hi: u32) -> AsciiChars {
+        let mut chars = self;
+        let mut c = lo;
+        while c <= hi {
+            chars = chars.__macro_push(c);
+            c += 1;
+        }
+        chars
     }
-}
-
-/// Provides a hook for a user-supplied fallback implementation, used
-/// when the optimized instructions are not available.
-///
-/// Although this implementation is a bit ungainly, Rust's closure
-/// inlining is top-notch and provides the best speed.
-#[derive(Debug,Copy,Clone)]
-pub struct AsciiCharsWithFallback<F> {
-    inner: AsciiChars,
-    fallback: F,
-}
 
-unsafe impl<F> DirectSearch for AsciiCharsWithFallback<F>
-    where F: Fn(u8) -> bool
-{
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn find(&self, haystack: &str) -> Option<usize> {
-        self.inner.find(haystack)
+    /// Add `c` to the end of the set to search for, or return an
+    /// error and leave the set unchanged if it is not ASCII.
+    ///
+    /// ### Panics
+    ///
+    /// - If you add more than 16 characters.
+    pub fn try_push(&mut self, c: char) -> Result<(), NotAsciiError> {
+        if !c.is_ascii() {
+            return Err(NotAsciiError { character: c });
+        }
+        self.push(c as u8);
+        Ok(())
     }
 
-    #[cfg(not(all(feature = "unstable", target_arch = "x86_64")))]
-    fn find(&self, haystack: &str) -> Option<usize> {
-        haystack.as_bytes().iter().cloned().position(&self.fallback)
+    /// Make the set match ASCII letters without regard to case, by
+    /// adding the other case of each letter that is missing it.
+    ///
+    /// ### Panics
+    ///
+    /// - If adding the other cases makes more than 16 characters.
+    #[must_use]
+    pub fn ascii_case_insensitive(self) -> AsciiChars {
+        let mut chars = self;
+        for byte in self {
+            let other = if byte.is_ascii_lowercase() {
+                byte.to_ascii_uppercase()
+            } else {
+                byte.to_ascii_lowercase()
+            };
+            if !chars.contains_byte(other) {
+                chars.push(other);
+            }
+        }
+        chars
     }
 
-    fn len(&self) -> usize {
-        1
+    /// A set that searches for every character in either set. The
+    /// characters of `self` keep their order, followed by those of
+    /// `other` that `self` is missing.
+    ///
+    /// ### Panics
+    ///
+    /// - If there are more than 16 distinct characters between them.
+    #[must_use]
+    pub fn union(self, other: AsciiChars) -> AsciiChars {
+        match self.try_union(other) {
+            Ok(chars) => chars,
+            Err(e) => panic!("{}", e),
+        }
     }
-}
 
-impl<'a, F> Pattern<'a> for AsciiCharsWithFallback<F>
-    where F: Fn(u8) -> bool
-{
-    type Searcher = DirectSearcher<'a, AsciiCharsWithFallback<F>>;
+    /// Like `union`, but returns an error if there are more than 16
+    /// distinct characters between the sets.
+    #[must_use]
+    pub fn try_union(self, other: AsciiChars) -> Result<AsciiChars, TooManyBytes> {
+        let mut chars = self;
+        let mut len = self.len();
+        for byte in other {
+            if !chars.contains_byte(byte) {
+                if chars.len() < MAXBYTES as usize {
+                    chars.push(byte);
+                }
+                len += 1;
+            }
+        }
+        if len > MAXBYTES as usize {
+            return Err(TooManyBytes { len: len });
+        }
+        Ok(chars)
+    }
 
-    fn into_searcher(self, haystack: &'a str) -> DirectSearcher<'a, AsciiCharsWithFallback<F>> {
-        // Assert that we are searching for only ascii
-        debug_assert!(self.inner.needle & !ASCII_WORD_MASK == 0);
-        debug_assert!(self.inner.needle_hi & !ASCII_WORD_MASK == 0);
+    /// A `ByteSet` that searches for the same characters. Duplicate
+    /// characters are dropped, as `ByteSet::new` does.
+    #[cfg(feature = "std")]
+    pub fn into_byte_set(self) -> ByteSet {
+        ByteSet::new(&self.into_iter().collect::<Vec<_>>())
+    }
 
-        DirectSearcher {
-            haystack: haystack,
+    /// Remove the first occurrence of `byte` from the set, moving the
+    /// characters after it down. Returns whether it was found.
+    pub fn remove(&mut self, byte: u8) -> bool {
+        let index = match (0..self.len()).position(|index| self.byte_at(index) == byte) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let packed = (self.needle_hi as u128) << 64 | self.needle as u128;
+        let below = packed & ((1 << (8 * index)) - 1);
+        let above = packed.checked_shr(8 * (index as u32 + 1)).unwrap_or(0) << (8 * index);
+        let packed = below | above;
+
+        self.needle = packed as u64;
+        self.needle_hi = (packed >> 64) as u64;
+        self.count = self.len() as u8 - 1;
+        true
+    }
+
+    /// Remove every character from the set.
+    pub fn clear(&mut self) {
+        *self = AsciiChars::new();
+    }
+
+    /// The number of characters in the set, which is also the number
+    /// of bytes the PCMPxSTRx instructions will use.
+    #[inline]
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        if self.count < MAXBYTES {
+            self.count as usize
+        } else {
+            MAXBYTES as usize
+        }
+    }
+
+    /// Whether the set has no characters.
+    #[inline]
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The byte at `index` of the packed words, as seen by the
+    /// PCMPxSTRx instructions.
+    #[inline]
+    const fn byte_at(&self, index: usize) -> u8 {
+        if index < 8 {
+            (self.needle >> (8 * index)) as u8
+        } else {
+            (self.needle_hi >> (8 * (index - 8))) as u8
+        }
+    }
+
+    /// Whether `byte` is one of the characters in the set.
+    #[inline]
+    #[must_use]
+    pub const fn contains_byte(&self, byte: u8) -> bool {
+        let mut index = 0;
+        while index < self.len() {
+            if self.byte_at(index) == byte {
+                return true;
+            }
+            index += 1;
+        }
+        false
+    }
+
+    /// One bit for each byte in the set, ignoring the order they were
+    /// added in.
+    #[inline]
+    fn members(&self) -> [u128; 2] {
+        let mut members = [0; 2];
+        for byte in self {
+            members[byte as usize / 128] |= 1 << (byte % 128);
+        }
+        members
+    }
+
+    /// Builds a searcher with a fallback implementation for when the
+    /// optimized version is not available. The fallback should search
+    /// for the **exact** same set of characters; debug builds check
+    /// every byte here and panic if it doesn't.
+    #[must_use]
+    pub fn with_fallback<F>(self, fallback: F) -> AsciiCharsWithFallback<F>
+        where F: Fn(u8) -> bool
+    {
+        let with_fallback = AsciiCharsWithFallback {
+            inner: self,
+            fallback: fallback,
+            fallback_forced: false,
+        };
+        with_fallback.debug_assert_consistent();
+        with_fallback
+    }
+
+    /// Builds a searcher with a fallback implementation that checks
+    /// each byte against exactly the characters in this set, so the
+    /// two can never disagree. The characters are looked up in a
+    /// bitset, so each check is the same speed however many there
+    /// are.
+    #[must_use]
+    pub fn with_generated_fallback(self) -> AsciiCharsWithFallback<impl Fn(u8) -> bool> {
+        let bits = self.bitset();
+        // Agrees with the set by construction, so skip the check
+        AsciiCharsWithFallback {
+            inner: self,
+            fallback: move |b: u8| bits[b as usize >> 6] >> (b & 63) & 1 != 0,
+            fallback_forced: false,
+        }
+    }
+
+    /// Like `with_fallback`, but the fallback is a plain function, so
+    /// this can be used to initialize a `static` searcher shared by
+    /// every thread. The fallback can't be called in a `const`, so
+    /// debug builds don't check that it agrees with the set.
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate jetscii;
+    ///
+    /// use jetscii::AsciiCharsWithFnFallback;
+    ///
+    /// fn is_xml_delimiter(b: u8) -> bool {
+    ///     b == b'<' || b == b'>' || b == b'&'
+    /// }
+    ///
+    /// static XML: AsciiCharsWithFnFallback =
+    ///     const_ascii_chars!('<', '>', '&').with_fn_fallback(is_xml_delimiter);
+    ///
+    /// fn main() {
+    ///     assert_eq!(Some(3), "abc&".find(XML));
+    /// }
+    /// ```
+    #[must_use]
+    pub const fn with_fn_fallback(self, fallback: fn(u8) -> bool) -> AsciiCharsWithFnFallback {
+        AsciiCharsWithFallback {
+            inner: self,
+            fallback: fallback,
+            fallback_forced: false,
+        }
+    }
+
+    /// The characters in the set as a 256-bit bitset, with bit
+    /// `b % 64` of word `b / 64` set for each byte `b`.
+    #[inline]
+    fn bitset(self) -> [u64; 4] {
+        let mut bits = [0u64; 4];
+        for byte in self {
+            bits[byte as usize >> 6] |= 1 << (byte & 63);
+        }
+        bits
+    }
+
+    /// Find the index of the first character in the set in a byte
+    /// slice, using the generated fallback when the optimized version
+    /// is not available. The characters are all ASCII, so the index is
+    /// a character boundary whenever the bytes are UTF-8.
+    #[inline]
+    #[must_use]
+    pub fn find_bytes<H>(self, haystack: H) -> Option<usize>
+        where H: AsRef<[u8]>
+    {
+        self.with_generated_fallback().find_bytes(haystack.as_ref())
+    }
+
+    /// Find the index of the first character in the set in the first
+    /// `initialized` bytes of `buf`, such as a read buffer that has
+    /// only been partly filled. The rest of the buffer isn't read.
+    ///
+    /// ### Safety
+    ///
+    /// The first `initialized` bytes of `buf` must be initialized.
+    ///
+    /// ### Panics
+    ///
+    /// - If `initialized` is more than the length of `buf`.
+    #[inline]
+    #[must_use]
+    pub unsafe fn find_bytes_init(self, buf: &[MaybeUninit<u8>], initialized: usize) -> Option<usize> {
+        assert!(initialized <= buf.len(),
+                "{} bytes can't be initialized in a buffer of {}",
+                initialized,
+                buf.len());
+        let init = slice::from_raw_parts(buf.as_ptr() as *const u8, initialized);
+        self.find_bytes(init)
+    }
+
+    /// Find the first character in the set, counting the `char`s
+    /// before it rather than the bytes. The characters are all ASCII,
+    /// so the match is always on a character boundary. Counting the
+    /// `char`s takes another pass over the bytes before the match, so
+    /// this is O(n) in the byte index, on top of the search.
+    #[must_use]
+    pub fn char_position(self, haystack: &str) -> Option<usize> {
+        self.find_bytes(haystack).map(|idx| haystack[..idx].chars().count())
+    }
+
+    /// Like `find_bytes`, but returns `sentinel` when no character in
+    /// the set is present, for callers such as C that can't take an
+    /// `Option`. The sentinel must not be a possible index, so it
+    /// should be at least the length of the haystack; `usize::MAX`
+    /// always works.
+    #[inline]
+    #[must_use]
+    pub fn find_bytes_or<H>(self, haystack: H, sentinel: usize) -> usize
+        where H: AsRef<[u8]>
+    {
+        self.find_bytes(haystack).unwrap_or(sentinel)
+    }
+
+    /// Like `find_bytes`, but skips the work for leading bytes that
+    /// are not 16-byte-aligned. This is only faster when the
+    /// PCMPxSTRx instructions are available; otherwise it is the same
+    /// as `find_bytes`.
+    ///
+    /// ### Safety
+    ///
+    /// `haystack` must start on a 16-byte boundary. Otherwise the last
+    /// read may run off the end of the byte slice. This is checked in
+    /// debug builds.
+    #[inline]
+    #[must_use]
+    pub unsafe fn find_bytes_aligned(self, haystack: &[u8]) -> Option<usize> {
+        debug_assert_eq!(0, haystack.as_ptr() as usize & 0xF, "haystack is not 16-byte-aligned");
+        self.find_bytes_aligned_unchecked(haystack)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline]
+    unsafe fn find_bytes_aligned_unchecked(self, haystack: &[u8]) -> Option<usize> {
+        if has_sse42() {
+            UnalignedByteSliceHandler { operation: self }.find_aligned(haystack)
+        } else {
+            self.find_bytes(haystack)
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline]
+    unsafe fn find_bytes_aligned_unchecked(self, haystack: &[u8]) -> Option<usize> {
+        self.find_bytes(haystack)
+    }
+
+    /// Like `find_bytes`, but prefetches the bytes `distance` bytes
+    /// ahead of the search as it goes. This can help with haystacks
+    /// much larger than the cache, where the search waits on memory.
+    /// Where there's no prefetch instruction, it is the same as
+    /// `find_bytes`.
+    #[must_use]
+    pub fn find_bytes_prefetched<H>(self, haystack: H, distance: usize) -> Option<usize>
+        where H: AsRef<[u8]>
+    {
+        const CHUNK_SIZE: usize = 256;
+
+        let haystack = haystack.as_ref();
+        let find = AsciiChars::select_find();
+        let start = haystack.as_ptr();
+
+        // Every chunk after the first starts on a chunk-aligned
+        // address, so only the first one has unaligned leading bytes.
+        let mut offset = 0;
+        let mut end = CHUNK_SIZE - start as usize % CHUNK_SIZE;
+
+        while offset < haystack.len() {
+            let end_of_chunk = min(end, haystack.len());
+            prefetch(start.wrapping_add(offset.saturating_add(distance)), CHUNK_SIZE);
+
+            if let Some(idx) = find(self, &haystack[offset..end_of_chunk]) {
+                return Some(offset + idx);
+            }
+
+            offset = end_of_chunk;
+            end += CHUNK_SIZE;
+        }
+        None
+    }
+
+    /// The search `find_bytes` would use, chosen once so that a loop
+    /// over many short slices doesn't check the CPU for each of them.
+    #[cfg(target_arch = "x86_64")]
+    fn select_find() -> fn(AsciiChars, &[u8]) -> Option<usize> {
+        if !has_sse42() {
+            return |chars, haystack| chars.with_generated_fallback().find_fallback(haystack);
+        }
+
+        #[cfg(feature = "avx512")]
+        {
+            if has_avx512bw() {
+                return |chars, haystack| unsafe { chars.find_avx512(haystack) };
+            }
+        }
+
+        if has_avx2() {
+            |chars, haystack| unsafe { chars.find_avx2(haystack) }
+        } else {
+            AsciiChars::find_sse
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn select_find() -> fn(AsciiChars, &[u8]) -> Option<usize> {
+        |chars, haystack| chars.with_generated_fallback().find_bytes(haystack)
+    }
+
+    /// Like `find_bytes`, but also returns which character in the set
+    /// was found.
+    #[inline]
+    #[must_use]
+    pub fn find_matching<H>(self, haystack: H) -> Option<(usize, u8)>
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        self.find_bytes(haystack).map(|idx| (idx, haystack[idx]))
+    }
+
+    /// Split the haystack around the first character in the set,
+    /// returning the bytes before it, the character, and the bytes
+    /// after it. Returns `None` if no character in the set is present.
+    #[must_use]
+    pub fn split_at_first<'a, H>(self, haystack: &'a H) -> Option<(&'a [u8], u8, &'a [u8])>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        let haystack = haystack.as_ref();
+        self.find_matching(haystack).map(|(idx, byte)| {
+            (&haystack[..idx], byte, &haystack[idx + 1..])
+        })
+    }
+
+    /// Like `find_matching`, but when nothing is found it says how
+    /// many bytes were searched, so all of them can be consumed.
+    #[inline]
+    #[must_use]
+    pub fn scan<H>(self, haystack: H) -> ScanResult
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        match self.find_matching(haystack) {
+            Some((index, byte)) => ScanResult::Found { index: index, byte: byte },
+            None => ScanResult::NotFound { scanned: haystack.len() },
+        }
+    }
+
+    /// Find the index of the `n`th character in the set, counting
+    /// from zero.
+    #[must_use]
+    pub fn find_nth<H>(self, haystack: H, n: usize) -> Option<usize>
+        where H: AsRef<[u8]>
+    {
+        self.match_indices(haystack.as_ref()).nth(n).map(|(idx, _)| idx)
+    }
+
+    /// Find the index of the first character in the set, looking at
+    /// no more than the first `limit` bytes.
+    #[inline]
+    #[must_use]
+    pub fn find_within<H>(self, haystack: H, limit: usize) -> Option<usize>
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        self.find_bytes(&haystack[..min(limit, haystack.len())])
+    }
+
+    /// Like `find_within`, but says whether the search stopped because
+    /// it ran out of haystack or out of budget. A search that reached
+    /// `max_bytes` can be carried on later from that index.
+    #[must_use]
+    pub fn find_with_budget<H>(self, haystack: H, max_bytes: usize) -> BudgetResult
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        let searched = min(max_bytes, haystack.len());
+
+        match self.find_bytes(&haystack[..searched]) {
+            Some(index) => BudgetResult::Found(index),
+            None if searched == haystack.len() => BudgetResult::Exhausted,
+            None => BudgetResult::BudgetReached(searched),
+        }
+    }
+
+    /// Write the indices of the characters in the set into `out`, in
+    /// order, stopping once it is full. Returns the number of indices
+    /// written; if that is `out.len()` there may be more matches.
+    pub fn positions_into<H>(self, haystack: H, out: &mut [usize]) -> usize
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        let mut written = 0;
+        let mut offset = 0;
+        while written < out.len() {
+            match self.find_bytes(&haystack[offset..]) {
+                Some(idx) => {
+                    out[written] = offset + idx;
+                    written += 1;
+                    offset += idx + 1;
+                }
+                None => break,
+            }
+        }
+        written
+    }
+
+    /// Call `f` with the index of every character in the set, in
+    /// order.
+    #[inline]
+    pub fn for_each_position<H, F>(self, haystack: H, mut f: F)
+        where H: AsRef<[u8]>,
+              F: FnMut(usize)
+    {
+        self.fold_positions(haystack, (), |(), idx| f(idx))
+    }
+
+    /// Combine the index of every character in the set into a single
+    /// value, in order, like `Iterator::fold`.
+    #[inline]
+    pub fn fold_positions<H, B, F>(self, haystack: H, init: B, mut f: F) -> B
+        where H: AsRef<[u8]>,
+              F: FnMut(B, usize) -> B
+    {
+        let haystack = haystack.as_ref();
+        let with_fallback = self.with_generated_fallback();
+        let mut acc = init;
+        let mut offset = 0;
+
+        while let Some(idx) = with_fallback.find_bytes(&haystack[offset..]) {
+            acc = f(acc, offset + idx);
+            offset += idx + 1;
+        }
+        acc
+    }
+
+    /// Count how many times each character in the set occurs, in a
+    /// single pass. Each count is at the index its character was added
+    /// at; a character added twice is counted at the first. The counts
+    /// add up to `count`.
+    #[must_use]
+    pub fn match_histogram<H>(self, haystack: H) -> [usize; MAXBYTES as usize]
+        where H: AsRef<[u8]>
+    {
+        // The index each byte was added at, so a match is counted
+        // with a single lookup.
+        let mut slots = [0u8; 256];
+        for index in (0..self.len()).rev() {
+            slots[self.byte_at(index) as usize] = index as u8;
+        }
+
+        let haystack = haystack.as_ref();
+        let mut histogram = [0; MAXBYTES as usize];
+        self.for_each_position(haystack, |idx| {
+            histogram[slots[haystack[idx] as usize] as usize] += 1;
+        });
+        histogram
+    }
+
+    /// Call `f` with the index and byte of each character in the set,
+    /// in order, and return the first value it gives, like
+    /// `Iterator::find_map`. No further matches are searched for once
+    /// `f` returns `Some`.
+    #[inline]
+    pub fn find_map_position<H, T, F>(self, haystack: H, mut f: F) -> Option<T>
+        where H: AsRef<[u8]>,
+              F: FnMut(usize, u8) -> Option<T>
+    {
+        let haystack = haystack.as_ref();
+        let with_fallback = self.with_generated_fallback();
+        let mut offset = 0;
+
+        while let Some(idx) = with_fallback.find_bytes(&haystack[offset..]) {
+            if let Some(found) = f(offset + idx, haystack[offset + idx]) {
+                return Some(found);
+            }
+            offset += idx + 1;
+        }
+        None
+    }
+
+    /// Count the characters in the set, splitting the haystack into
+    /// chunks that are counted in parallel on the rayon thread pool.
+    /// The result is the same as counting sequentially.
+    ///
+    /// Only counting is parallelized; operations that depend on the
+    /// order of the matches, such as `find`, still scan sequentially.
+    #[cfg(feature = "rayon")]
+    #[must_use]
+    pub fn par_count<H>(self, haystack: H) -> usize
+        where H: AsRef<[u8]>
+    {
+        use rayon::prelude::*;
+
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let with_fallback = self.with_generated_fallback();
+        haystack.as_ref().par_chunks(CHUNK_SIZE)
+            .map(|chunk| with_fallback.count_bytes(chunk))
+            .sum()
+    }
+
+    #[inline]
+    fn rfind_bytes(self, haystack: &[u8]) -> Option<usize> {
+        self.with_generated_fallback().rfind_bytes(haystack)
+    }
+
+    /// Read from `reader` until a character in the set is found,
+    /// consuming everything up to and including it. Returns the index
+    /// of the character from where the reader was, or `None` if the
+    /// reader ran out first.
+    #[cfg(feature = "std")]
+    pub fn find_in_reader<R>(self, reader: &mut R) -> io::Result<Option<usize>>
+        where R: BufRead
+    {
+        let mut offset = 0;
+
+        loop {
+            let (consumed, found) = {
+                let chunk = match reader.fill_buf() {
+                    Ok(chunk) => chunk,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+
+                if chunk.is_empty() {
+                    return Ok(None);
+                }
+
+                match self.find_bytes(chunk) {
+                    Some(idx) => (idx + 1, Some(offset + idx)),
+                    None => (chunk.len(), None),
+                }
+            };
+
+            reader.consume(consumed);
+            if found.is_some() {
+                return Ok(found);
+            }
+            offset += consumed;
+        }
+    }
+
+    /// Find the index of the first character in the set in the bytes
+    /// of `chunks` one after the other, as if they had been
+    /// concatenated. A slice of `IoSlice`s can be searched with
+    /// `slices.iter().map(|s| &**s)`.
+    #[must_use]
+    pub fn find_in_chunks<'c, I>(self, chunks: I) -> Option<usize>
+        where I: IntoIterator<Item = &'c [u8]>
+    {
+        let searcher = self.with_generated_fallback();
+        let mut offset = 0;
+
+        for chunk in chunks {
+            if let Some(idx) = searcher.find_bytes(chunk) {
+                return Some(offset + idx);
+            }
+            offset += chunk.len();
+        }
+        None
+    }
+
+    /// Iterate over the parts of `haystack` separated by characters
+    /// in the set, like `[u8]::split`. A separator at the end yields a
+    /// final empty part, as does an empty haystack.
+    pub fn split<'a, H>(self, haystack: &'a H) -> Split<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        Split {
+            haystack: haystack.as_ref(),
+            chars: self,
+            finished: false,
+        }
+    }
+
+    /// Like `split`, but yields at most `n` parts. The last part
+    /// contains the rest of the haystack.
+    pub fn splitn<'a, H>(self, haystack: &'a H, n: usize) -> SplitN<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        SplitN {
+            inner: self.split(haystack),
+            count: n,
+        }
+    }
+
+    /// Like `split`, but each part keeps the character that ends it,
+    /// like `[u8]::split_inclusive`. The last part doesn't have to end
+    /// with one, and an empty haystack has no parts.
+    pub fn split_inclusive<'a, H>(self, haystack: &'a H) -> SplitInclusive<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        SplitInclusive {
+            haystack: haystack.as_ref(),
+            chars: self,
+        }
+    }
+
+    /// Like `split`, but each part also says which character in the
+    /// set ended it and where. The last part isn't ended by one. The
+    /// parts and characters together make up the whole haystack.
+    pub fn segments<'a, H>(self, haystack: &'a H) -> Segments<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        Segments {
+            haystack: haystack.as_ref(),
             offset: 0,
-            direct_search: self,
+            chars: self,
+            finished: false,
         }
     }
-}
 
-/// Search a string for a substring.
-#[derive(Debug,Copy,Clone)]
-pub struct Substring<'a> {
-    raw: &'a str,
-    needle_lo: u64,
-    needle_hi: u64,
-    needle_len: u8,
-}
+    /// Like `split`, but starts from the end of the haystack.
+    pub fn rsplit<'a, H>(self, haystack: &'a H) -> RSplit<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        RSplit { inner: self.split(haystack) }
+    }
 
-impl<'a> Substring<'a> {
-    pub fn new(needle: &'a str) -> Substring<'a> {
-        fn pack_needle_bytes(bytes: &[u8]) -> u64 {
-            let mut needle = 0;
-            for &b in bytes.iter().rev() {
-                needle <<= 8;
-                needle |= b as u64;
+    /// Like `rsplit`, but yields at most `n` parts. The last part
+    /// contains the start of the haystack.
+    pub fn rsplitn<'a, H>(self, haystack: &'a H, n: usize) -> RSplitN<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        RSplitN {
+            inner: self.split(haystack),
+            count: n,
+        }
+    }
+
+    /// Copy `haystack`, replacing every character in the set with
+    /// `replacement`.
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub fn replace_all(self, haystack: &[u8], replacement: u8) -> Vec<u8> {
+        let mut replaced = haystack.to_vec();
+        self.replace_all_in_place(&mut replaced, replacement);
+        replaced
+    }
+
+    /// Like `replace_all`, but only copies `haystack` if it has a
+    /// character in the set; otherwise it is borrowed.
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub fn replace_all_cow<'a>(self, haystack: &'a [u8], replacement: u8) -> Cow<'a, [u8]> {
+        match self.find_bytes(haystack) {
+            None => Cow::Borrowed(haystack),
+            Some(idx) => {
+                let mut replaced = haystack.to_vec();
+                replaced[idx] = replacement;
+                self.replace_all_in_place(&mut replaced[idx + 1..], replacement);
+                Cow::Owned(replaced)
             }
-            needle
         }
+    }
+
+    /// Replace every character in the set with `replacement`, without
+    /// allocating. Returns how many bytes were replaced.
+    pub fn replace_all_in_place(self, haystack: &mut [u8], replacement: u8) -> usize {
+        let mut count = 0;
+        self.for_each_position_mut(haystack, |_, byte| {
+            *byte = replacement;
+            count += 1;
+        });
+        count
+    }
+
+    /// Call `f` with the index of every character in the set, in
+    /// order, along with the byte itself to rewrite. The search
+    /// carries on after the byte, so whatever `f` writes is never
+    /// matched again.
+    pub fn for_each_position_mut<F>(self, haystack: &mut [u8], mut f: F)
+        where F: FnMut(usize, &mut u8)
+    {
+        let with_fallback = self.with_generated_fallback();
+        let mut offset = 0;
+
+        while let Some(idx) = with_fallback.find_bytes(&haystack[offset..]) {
+            f(offset + idx, &mut haystack[offset + idx]);
+            offset += idx + 1;
+        }
+    }
+
+    /// Which of exactly 16 bytes are in the set: bit `i` of the result
+    /// is set when `window[i]` is, so the lowest set bit is the first
+    /// match and `count_ones` is the number of matches. This is the
+    /// mask of the PCMPESTRM instruction when it is available, and is
+    /// built a byte at a time otherwise.
+    #[cfg(target_arch = "x86_64")]
+    #[inline]
+    #[must_use]
+    pub fn match_mask(self, window: &[u8; 16]) -> u16 {
+        if has_sse42() {
+            unsafe { self.initial(window.as_ptr(), 0, 16) as u16 }
+        } else {
+            self.match_mask_bytewise(window)
+        }
+    }
+
+    /// Which of exactly 16 bytes are in the set: bit `i` of the result
+    /// is set when `window[i]` is, so the lowest set bit is the first
+    /// match and `count_ones` is the number of matches.
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn match_mask(self, window: &[u8; 16]) -> u16 {
+        self.match_mask_bytewise(window)
+    }
+
+    fn match_mask_bytewise(self, window: &[u8; 16]) -> u16 {
+        let bits = self.bitset();
+        window.iter().enumerate().fold(0, |mask, (i, &b)| {
+            mask | ((bits[b as usize >> 6] >> (b & 63) & 1) as u16) << i
+        })
+    }
+
+    /// Whether every byte of `haystack` is in the set, stopping at the
+    /// first one that isn't. An empty haystack is all in the set.
+    #[must_use]
+    pub fn all_in_set<H>(self, haystack: H) -> bool
+        where H: AsRef<[u8]>
+    {
+        self.with_generated_fallback().find_not_bytes(haystack.as_ref()).is_none()
+    }
+
+    /// Remove the leading bytes that are in the set. A haystack made
+    /// entirely of them trims to an empty slice.
+    #[must_use]
+    pub fn trim_start<'a, H>(self, haystack: &'a H) -> &'a [u8]
+        where H: AsRef<[u8]> + ?Sized
+    {
+        let haystack = haystack.as_ref();
+        let start = self.with_generated_fallback().find_not_bytes(haystack);
+        &haystack[start.unwrap_or(haystack.len())..]
+    }
+
+    /// Remove the trailing bytes that are in the set. A haystack made
+    /// entirely of them trims to an empty slice.
+    #[must_use]
+    pub fn trim_end<'a, H>(self, haystack: &'a H) -> &'a [u8]
+        where H: AsRef<[u8]> + ?Sized
+    {
+        let haystack = haystack.as_ref();
+        let end = self.with_generated_fallback().rfind_not_bytes(haystack);
+        &haystack[..end.map_or(0, |idx| idx + 1)]
+    }
+
+    /// Remove both the leading and trailing bytes that are in the set.
+    #[must_use]
+    pub fn trim<'a, H>(self, haystack: &'a H) -> &'a [u8]
+        where H: AsRef<[u8]> + ?Sized
+    {
+        self.trim_end(self.trim_start(haystack))
+    }
+
+    /// Iterate over the index of every character in the set, along
+    /// with which character it was, like `str::match_indices`.
+    pub fn match_indices<'a, H>(self, haystack: &'a H) -> MatchIndices<'a>
+        where H: AsRef<[u8]> + ?Sized
+    {
+        MatchIndices {
+            haystack: haystack.as_ref(),
+            offset: 0,
+            chars: self,
+        }
+    }
+
+    /// Find the index of the first character in the set.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn find(self, haystack: &str) -> Option<usize> {
+        self.find_optimized(haystack.as_bytes())
+    }
+
+    /// Find the index of the first character in the set at or after
+    /// `start`. Returns `None` if `start` is past the end.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn find_from(self, haystack: &str, start: usize) -> Option<usize> {
+        haystack.as_bytes().get(start..)
+            .and_then(|tail| self.find_optimized(tail))
+            .map(|idx| start + idx)
+    }
+
+    /// Find the index of the last character in the set.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn rfind(self, haystack: &str) -> Option<usize> {
+        UnalignedByteSliceHandler { operation: self }.rfind(haystack.as_bytes())
+    }
+
+    /// Iterate over the indices of every character in the set.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    pub fn positions<'a>(self, haystack: &'a str) -> Positions<'a, AsciiChars> {
+        Positions::new(haystack, self)
+    }
+
+    /// Count the characters in the set.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn count<H>(self, haystack: H) -> usize
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        if self.len() == 1 {
+            return count_byte(self.byte_at(0), haystack);
+        }
+        UnalignedByteSliceHandler { operation: self }.count(haystack)
+    }
+
+    /// Check if any character in the set is present.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn contains<H>(self, haystack: H) -> bool
+        where H: AsRef<[u8]>
+    {
+        UnalignedByteSliceHandler { operation: self }.contains(haystack.as_ref())
+    }
+
+    /// Find the index of the first byte that is not in the set.
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    #[inline]
+    #[must_use]
+    pub fn find_not(self, haystack: &str) -> Option<usize> {
+        UnalignedByteSliceHandler { operation: self }.find_not(haystack.as_bytes())
+    }
+}
+
+/// What `AsciiChars::scan` found.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum ScanResult {
+    /// The first character in the set is `byte`, at `index`. None of
+    /// the bytes before it are in the set.
+    Found { index: usize, byte: u8 },
+    /// None of the `scanned` bytes, which is all of them, are in the
+    /// set.
+    NotFound { scanned: usize },
+}
+
+/// What `AsciiChars::find_with_budget` found.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum BudgetResult {
+    /// The first character in the set is at this index.
+    Found(usize),
+    /// None of the bytes are in the set, and all of them were
+    /// searched.
+    Exhausted,
+    /// None of the bytes before this index are in the set, but the
+    /// rest weren't searched.
+    BudgetReached(usize),
+}
+
+/// Shows the characters in the set, in the order they were added. The
+/// alternate form (`{:#?}`) also shows the packed words that the
+/// PCMPxSTRx instructions use.
+impl fmt::Debug for AsciiChars {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alternate = f.alternate();
+        let mut s = f.debug_struct("AsciiChars");
+        s.field("bytes", &DebugBytes(*self));
+        s.field("count", &self.count);
+        if alternate {
+            s.field("lo", &DebugWord(self.needle));
+            s.field("hi", &DebugWord(self.needle_hi));
+        }
+        s.finish()
+    }
+}
+
+struct DebugBytes(AsciiChars);
+
+impl fmt::Debug for DebugBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.into_iter().map(DebugByte)).finish()
+    }
+}
+
+/// A byte as a byte literal when it's printable ASCII, or in hex.
+struct DebugByte(u8);
+
+impl fmt::Debug for DebugByte {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            b'\'' => write!(f, "b'\\''"),
+            b'\\' => write!(f, "b'\\\\'"),
+            0x20..=0x7e => write!(f, "b'{}'", self.0 as char),
+            byte => write!(f, "0x{:02x}", byte),
+        }
+    }
+}
+
+struct DebugWord(u64);
+
+impl fmt::Debug for DebugWord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:016x}", self.0)
+    }
+}
+
+/// Two sets are equal when they search for the same characters and
+/// were given the same number of them; the order they were added in
+/// is not significant.
+impl PartialEq for AsciiChars {
+    fn eq(&self, other: &AsciiChars) -> bool {
+        self.len() == other.len() && self.members() == other.members()
+    }
+}
+
+impl Eq for AsciiChars {}
+
+impl Hash for AsciiChars {
+    fn hash<H>(&self, state: &mut H)
+        where H: Hasher
+    {
+        self.len().hash(state);
+        self.members().hash(state);
+    }
+}
+
+/// Collects bytes with `push`, keeping any duplicates.
+///
+/// ### Panics
+///
+/// - If there are more than 16 bytes.
+/// - If any byte is non-ASCII.
+impl FromIterator<u8> for AsciiChars {
+    fn from_iter<I>(iter: I) -> AsciiChars
+        where I: IntoIterator<Item = u8>
+    {
+        let mut chars = AsciiChars::new();
+        chars.extend(iter);
+        chars
+    }
+}
+
+/// Builds the set with `from_bytes`, so `AsciiChars::from(b"<>&")`
+/// searches for each of the bytes.
+///
+/// ### Panics
+///
+/// - If there are more than 16 bytes.
+/// - If any byte is non-ASCII.
+impl<'a, const N: usize> From<&'a [u8; N]> for AsciiChars {
+    fn from(bytes: &'a [u8; N]) -> AsciiChars {
+        AsciiChars::from_bytes(bytes)
+    }
+}
+
+/// Builds the set with `try_from_bytes`, which returns an error
+/// instead of panicking when there are more than 16 bytes.
+///
+/// ### Panics
+///
+/// - If any byte is non-ASCII.
+impl<'a> TryFrom<&'a [u8]> for AsciiChars {
+    type Error = TooManyBytes;
+
+    fn try_from(bytes: &'a [u8]) -> Result<AsciiChars, TooManyBytes> {
+        AsciiChars::try_from_bytes(bytes)
+    }
+}
+
+/// Builds a set of the single byte with `push`.
+///
+/// ### Panics
+///
+/// - If the byte is non-ASCII.
+impl From<u8> for AsciiChars {
+    fn from(byte: u8) -> AsciiChars {
+        let mut chars = AsciiChars::new();
+        chars.push(byte);
+        chars
+    }
+}
+
+/// Builds a set of the single character with `try_push`. Use
+/// `try_from_chars` to handle non-ASCII characters without panicking.
+///
+/// ### Panics
+///
+/// - If the character is non-ASCII.
+impl From<char> for AsciiChars {
+    fn from(c: char) -> AsciiChars {
+        let mut chars = AsciiChars::new();
+        if let Err(e) = chars.try_push(c) {
+            panic!("{}", e);
+        }
+        chars
+    }
+}
+
+/// Adds bytes with `push`, keeping any duplicates.
+///
+/// ### Panics
+///
+/// - If the set would have more than 16 bytes.
+/// - If any byte is non-ASCII.
+impl Extend<u8> for AsciiChars {
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = u8>
+    {
+        for byte in iter {
+            self.push(byte);
+        }
+    }
+}
+
+/// Yields the characters in the set, in the order they were added.
+impl IntoIterator for AsciiChars {
+    type Item = u8;
+    type IntoIter = AsciiCharsIter;
+
+    fn into_iter(self) -> AsciiCharsIter {
+        AsciiCharsIter {
+            chars: self,
+            index: 0,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a AsciiChars {
+    type Item = u8;
+    type IntoIter = AsciiCharsIter;
+
+    fn into_iter(self) -> AsciiCharsIter {
+        (*self).into_iter()
+    }
+}
+
+/// An iterator over the characters in an `AsciiChars`.
+#[derive(Debug,Copy,Clone)]
+pub struct AsciiCharsIter {
+    chars: AsciiChars,
+    index: usize,
+}
+
+impl Iterator for AsciiCharsIter {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.index < self.chars.len() {
+            let byte = self.chars.byte_at(self.index);
+            self.index += 1;
+            Some(byte)
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.chars.len() - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for AsciiCharsIter {}
+
+impl FusedIterator for AsciiCharsIter {}
+
+/// The error returned when more than 16 bytes are given to
+/// `AsciiChars::try_from_bytes` or `AsciiChars::try_from`, when
+/// `AsciiChars::try_union` would need more than 16, or when
+/// `ByteSet::try_into_ascii_chars` has more than 16.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct TooManyBytes {
+    len: usize,
+}
+
+impl fmt::Display for TooManyBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "AsciiChars can search for at most {} bytes, but {} were given",
+               MAXBYTES,
+               self.len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for TooManyBytes {}
+
+/// The error returned when a non-ASCII character is given to
+/// `AsciiChars::try_push` or `AsciiChars::try_from_chars`.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct NotAsciiError {
+    character: char,
+}
+
+impl fmt::Display for NotAsciiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "AsciiChars can only search for ASCII characters, not {:?}",
+               self.character)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for NotAsciiError {}
+
+/// The error returned when an empty needle is given to
+/// `Substring::try_new`.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct EmptyNeedle;
+
+impl fmt::Display for EmptyNeedle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Substring can't search for an empty needle")
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for EmptyNeedle {}
+
+/// Serialized as the bytes in the set, in the order they were added.
+#[cfg(feature = "serde")]
+impl serde::Serialize for AsciiChars {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let mut bytes = [0; MAXBYTES as usize];
+        for (slot, byte) in bytes.iter_mut().zip(*self) {
+            *slot = byte;
+        }
+        serializer.serialize_bytes(&bytes[..self.len()])
+    }
+}
+
+/// Deserialized from the bytes in the set. More than 16 bytes, or a
+/// non-ASCII byte, is an error.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for AsciiChars {
+    fn deserialize<D>(deserializer: D) -> Result<AsciiChars, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        use serde::de::{Error, SeqAccess, Unexpected, Visitor};
+
+        struct AsciiCharsVisitor;
+
+        impl<'de> Visitor<'de> for AsciiCharsVisitor {
+            type Value = AsciiChars;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "at most {} ASCII bytes", MAXBYTES)
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<AsciiChars, E>
+                where E: Error
+            {
+                if let Some(&byte) = bytes.iter().find(|&&b| b >= 128) {
+                    return Err(E::invalid_value(Unexpected::Unsigned(byte as u64), &self));
+                }
+                AsciiChars::try_from_bytes(bytes).map_err(|_| E::invalid_length(bytes.len(), &self))
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<AsciiChars, A::Error>
+                where A: SeqAccess<'de>
+            {
+                // Formats without a byte string type use a sequence
+                let mut bytes = [0; MAXBYTES as usize];
+                let mut len = 0;
+                while let Some(byte) = seq.next_element()? {
+                    if len == bytes.len() {
+                        return Err(A::Error::invalid_length(len + 1, &self));
+                    }
+                    bytes[len] = byte;
+                    len += 1;
+                }
+                self.visit_bytes(&bytes[..len])
+            }
+        }
+
+        deserializer.deserialize_bytes(AsciiCharsVisitor)
+    }
+}
+
+/// Without a fallback of its own, the generated fallback is used when
+/// the optimized version is not available.
+unsafe impl DirectSearch for AsciiChars {
+    fn find(&self, haystack: &str) -> Option<usize> {
+        self.find_bytes(haystack.as_bytes())
+    }
+
+    fn len(&self) -> usize {
+        1
+    }
+}
+
+impl<'a> Pattern<'a> for AsciiChars {
+    type Searcher = DirectSearcher<'a, AsciiChars>;
+
+    fn into_searcher(self, haystack: &'a str) -> DirectSearcher<'a, AsciiChars> {
+        // Assert that we are searching for only ascii
+        debug_assert!(self.needle & !ASCII_WORD_MASK == 0);
+        debug_assert!(self.needle_hi & !ASCII_WORD_MASK == 0);
+
+        DirectSearcher {
+            haystack: haystack,
+            offset: 0,
+            direct_search: self,
+            matched_empty: false,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PackedCompareOperation for AsciiChars {
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn initial(&self, ptr: *const u8, offset: usize, len: usize) -> u64 {
+        let needle = _mm_set_epi64x(self.needle_hi as i64, self.needle as i64);
+        let haystack = _mm_loadu_si128(ptr as *const __m128i);
+
+        let matching_bytes = _mm_cmpestrm::<EQUAL_ANY>(needle,
+                                                       self.count as i32,
+                                                       haystack,
+                                                       min(offset + len, 16) as i32); // saturates at 16
+
+        _mm_cvtsi128_si64(matching_bytes) as u64
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn body(&self, ptr: *const u8, offset: usize, len: usize) -> u32 {
+        let needle = _mm_set_epi64x(self.needle_hi as i64, self.needle as i64);
+        let haystack = _mm_loadu_si128(ptr.offset(offset as isize) as *const __m128i);
+
+        _mm_cmpestri::<EQUAL_ANY>(needle,
+                                  self.count as i32,       // needle length
+                                  haystack,
+                                  min(len, 16) as i32) as u32 // haystack length
+    }
+}
+
+/// A SIMD width for `AsciiChars::find_bytes_with_lanes`: `Lanes<16>`
+/// searches with the SSE 4.2 PCMPxSTRx instructions, `Lanes<32>`
+/// with AVX2 and, with the `avx512` feature, `Lanes<64>` with
+/// AVX-512. Other widths don't implement `SupportedLanes`, so asking
+/// for them doesn't compile.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug,Copy,Clone)]
+pub struct Lanes<const N: usize>;
+
+/// The widths of `Lanes` that can be searched with.
+#[cfg(target_arch = "x86_64")]
+pub trait SupportedLanes {
+    #[doc(hidden)]
+    unsafe fn find(chars: AsciiChars, haystack: &[u8]) -> Option<usize>;
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SupportedLanes for Lanes<16> {
+    #[inline]
+    unsafe fn find(chars: AsciiChars, haystack: &[u8]) -> Option<usize> {
+        UnalignedByteSliceHandler { operation: chars }.find(haystack)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SupportedLanes for Lanes<32> {
+    #[inline]
+    unsafe fn find(chars: AsciiChars, haystack: &[u8]) -> Option<usize> {
+        chars.find_avx2(haystack)
+    }
+}
+
+#[cfg(all(feature = "avx512", target_arch = "x86_64"))]
+impl SupportedLanes for Lanes<64> {
+    #[inline]
+    unsafe fn find(chars: AsciiChars, haystack: &[u8]) -> Option<usize> {
+        chars.find_avx512(haystack)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl AsciiChars {
+    /// Find the index of the first character in the set, searching
+    /// `LANES` bytes at a time whatever the CPU is detected to
+    /// support. `find_bytes` remains the way to get the best search
+    /// for the CPU.
+    ///
+    /// ### Safety
+    ///
+    /// The CPU must support SSE 4.2, AVX2 as well for `Lanes<32>`, and
+    /// AVX-512BW as well for `Lanes<64>`.
+    #[inline]
+    #[must_use]
+    pub unsafe fn find_bytes_with_lanes<const LANES: usize>(self, haystack: &[u8]) -> Option<usize>
+        where Lanes<LANES>: SupportedLanes
+    {
+        Lanes::<LANES>::find(self, haystack)
+    }
+
+    /// Find the index of the first character in the set using the
+    /// widest instructions the CPU supports. Assumes that the
+    /// PCMPxSTRx instructions are available.
+    #[inline]
+    fn find_optimized(self, haystack: &[u8]) -> Option<usize> {
+        #[cfg(feature = "avx512")]
+        {
+            if has_avx512bw() {
+                return unsafe { self.find_avx512(haystack) };
+            }
+        }
+
+        if has_avx2() {
+            unsafe { self.find_avx2(haystack) }
+        } else {
+            self.find_sse(haystack)
+        }
+    }
+
+    /// Find the index of the first character in the set 16 bytes at a
+    /// time. A single character is compared directly, which is cheaper
+    /// than setting up the PCMPESTRI instruction.
+    #[inline]
+    fn find_sse(self, haystack: &[u8]) -> Option<usize> {
+        if self.len() == 1 {
+            find_byte_sse2(self.byte_at(0), haystack)
+        } else {
+            UnalignedByteSliceHandler { operation: self }.find(haystack)
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_avx2(self, haystack: &[u8]) -> Option<usize> {
+        use core::arch::x86_64::{__m256i, _mm256_cmpeq_epi8, _mm256_loadu_si256,
+                                _mm256_movemask_epi8, _mm256_or_si256, _mm256_set1_epi8,
+                                _mm256_setzero_si256};
+
+        // Each needle byte is broadcast across its own register so
+        // that a window can be compared against every one of them.
+        let count = self.len();
+        let mut needles = [_mm256_setzero_si256(); 16];
+        for (index, needle) in needles[..count].iter_mut().enumerate() {
+            *needle = _mm256_set1_epi8(self.byte_at(index) as i8);
+        }
+
+        // Only read windows that lie entirely within the byte slice;
+        // the PCMPESTRI path searches whatever is left over.
+        let mut offset = 0;
+
+        while haystack.len() - offset >= 32 {
+            let ptr = haystack.as_ptr().offset(offset as isize) as *const __m256i;
+            let window = _mm256_loadu_si256(ptr);
+
+            let mut matches = _mm256_setzero_si256();
+            for &needle in &needles[..count] {
+                matches = _mm256_or_si256(matches, _mm256_cmpeq_epi8(window, needle));
+            }
+
+            let matching_bytes = _mm256_movemask_epi8(matches) as u32;
+            if matching_bytes != 0 {
+                return Some(offset + matching_bytes.trailing_zeros() as usize);
+            }
+
+            offset += 32;
+        }
+
+        self.find_sse(&haystack[offset..]).map(|idx| offset + idx)
+    }
+
+    #[cfg(feature = "avx512")]
+    #[target_feature(enable = "avx512bw")]
+    unsafe fn find_avx512(self, haystack: &[u8]) -> Option<usize> {
+        use core::arch::x86_64::{_mm512_cmpeq_epi8_mask, _mm512_loadu_si512, _mm512_set1_epi8,
+                                _mm512_setzero_si512};
+
+        let count = self.len();
+        let mut needles = [_mm512_setzero_si512(); 16];
+        for (index, needle) in needles[..count].iter_mut().enumerate() {
+            *needle = _mm512_set1_epi8(self.byte_at(index) as i8);
+        }
+
+        // Only read windows that lie entirely within the byte slice;
+        // the AVX2 path searches whatever is left over.
+        let mut offset = 0;
+
+        while haystack.len() - offset >= 64 {
+            let ptr = haystack.as_ptr().offset(offset as isize);
+            let window = _mm512_loadu_si512(ptr as *const _);
+
+            // Each comparison gives a bit for every byte that matched
+            let mut matching_bytes = 0u64;
+            for &needle in &needles[..count] {
+                matching_bytes |= _mm512_cmpeq_epi8_mask(window, needle);
+            }
+
+            if matching_bytes != 0 {
+                return Some(offset + matching_bytes.trailing_zeros() as usize);
+            }
+
+            offset += 64;
+        }
+
+        self.find_avx2(&haystack[offset..]).map(|idx| offset + idx)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl AsciiChars {
+    /// Find the index of the first character in the set, comparing
+    /// 16 bytes at a time with NEON instructions.
+    fn find_neon(self, haystack: &[u8]) -> Option<usize> {
+        use core::arch::aarch64::{uint8x16_t, vceqq_u8, vdupq_n_u8, vget_lane_u64, vld1q_u8,
+                                 vmaxvq_u8, vorrq_u8, vreinterpret_u64_u8,
+                                 vreinterpretq_u16_u8, vshrn_n_u16};
+
+        let count = self.len();
+        let mut offset = 0;
+
+        unsafe {
+            // Each needle byte is duplicated across its own register
+            // so that a window can be compared against every one of
+            // them.
+            let mut needles: [uint8x16_t; 16] = [vdupq_n_u8(0); 16];
+            for (index, needle) in needles[..count].iter_mut().enumerate() {
+                *needle = vdupq_n_u8(self.byte_at(index));
+            }
+
+            // Only read windows that lie entirely within the byte
+            // slice; whatever is left over is searched a byte at a
+            // time.
+            while haystack.len() - offset >= 16 {
+                let window = vld1q_u8(haystack.as_ptr().offset(offset as isize));
+
+                let mut matches = vdupq_n_u8(0);
+                for &needle in &needles[..count] {
+                    matches = vorrq_u8(matches, vceqq_u8(window, needle));
+                }
+
+                if vmaxvq_u8(matches) != 0 {
+                    // Narrow every byte of the comparison to 4 bits so
+                    // that the whole window fits into one word.
+                    let narrowed = vshrn_n_u16::<4>(vreinterpretq_u16_u8(matches));
+                    let matching_nibbles = vget_lane_u64::<0>(vreinterpret_u64_u8(narrowed));
+                    return Some(offset + (matching_nibbles.trailing_zeros() / 4) as usize);
+                }
+
+                offset += 16;
+            }
+        }
+
+        haystack[offset..]
+            .iter()
+            .position(|&b| self.contains_byte(b))
+            .map(|idx| offset + idx)
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+impl AsciiChars {
+    /// Find the index of the first character in the set, comparing
+    /// 16 bytes at a time with WebAssembly SIMD instructions.
+    fn find_simd128(self, haystack: &[u8]) -> Option<usize> {
+        use core::arch::wasm32::{v128, v128_load, v128_or, u8x16_bitmask, u8x16_eq, u8x16_splat};
+
+        let count = self.len();
+        let mut offset = 0;
+
+        // Each needle byte is splatted across its own register so
+        // that a window can be compared against every one of them.
+        let mut needles: [v128; 16] = [u8x16_splat(0); 16];
+        for (index, needle) in needles[..count].iter_mut().enumerate() {
+            *needle = u8x16_splat(self.byte_at(index));
+        }
+
+        // Only read windows that lie entirely within the byte slice;
+        // whatever is left over is searched a byte at a time.
+        while haystack.len() - offset >= 16 {
+            let window = unsafe {
+                v128_load(haystack.as_ptr().offset(offset as isize) as *const v128)
+            };
+
+            let mut matches = u8x16_splat(0);
+            for &needle in &needles[..count] {
+                matches = v128_or(matches, u8x16_eq(window, needle));
+            }
+
+            let matching_bytes = u8x16_bitmask(matches);
+            if matching_bytes != 0 {
+                return Some(offset + matching_bytes.trailing_zeros() as usize);
+            }
+
+            offset += 16;
+        }
+
+        haystack[offset..]
+            .iter()
+            .position(|&b| self.contains_byte(b))
+            .map(|idx| offset + idx)
+    }
+}
+
+/// A searcher whose fallback is a plain function. It is `Send` and
+/// `Sync` and can be built in a `static` with
+/// `AsciiChars::with_fn_fallback`.
+pub type AsciiCharsWithFnFallback = AsciiCharsWithFallback<fn(u8) -> bool>;
+
+/// Provides a hook for a user-supplied fallback implementation, used
+/// when the optimized instructions are not available.
+///
+/// Although this implementation is a bit ungainly, Rust's closure
+/// inlining is top-notch and provides the best speed.
+#[derive(Debug,Copy,Clone)]
+pub struct AsciiCharsWithFallback<F> {
+    inner: AsciiChars,
+    fallback: F,
+    // Whether to search with the fallback even when the CPU supports
+    // something faster.
+    fallback_forced: bool,
+}
+
+impl<F> AsciiCharsWithFallback<F>
+    where F: Fn(u8) -> bool
+{
+    /// Search with the fallback, even when the CPU supports something
+    /// faster, until this is called again with `false`. This is for
+    /// comparing the two searches on the same machine; by default the
+    /// fastest search is used.
+    pub fn force_fallback(&mut self, yes: bool) {
+        self.fallback_forced = yes;
+    }
+
+    /// Whether the PCMPxSTRx instructions should be used.
+    #[cfg(target_arch = "x86_64")]
+    #[inline]
+    fn use_sse42(&self) -> bool {
+        !self.fallback_forced && has_sse42()
+    }
+
+    /// Find the index of the first character in the set, using the
+    /// fastest search the CPU supports.
+    #[cfg(target_arch = "x86_64")]
+    fn find_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if self.use_sse42() {
+            self.inner.find_optimized(haystack)
+        } else {
+            self.find_fallback(haystack)
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn find_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if self.fallback_forced {
+            haystack.iter().cloned().position(&self.fallback)
+        } else {
+            self.inner.find_neon(haystack)
+        }
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+    fn find_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if self.fallback_forced {
+            haystack.iter().cloned().position(&self.fallback)
+        } else {
+            self.inner.find_simd128(haystack)
+        }
+    }
+
+    #[cfg(not(any(target_arch = "x86_64",
+                  target_arch = "aarch64",
+                  all(target_arch = "wasm32", target_feature = "simd128"))))]
+    fn find_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        self.find_fallback(haystack)
+    }
+
+    /// Find the index of the first character in the set at or after
+    /// `start`. Returns `None` if `start` is past the end.
+    #[must_use]
+    pub fn find_from(&self, haystack: &str, start: usize) -> Option<usize> {
+        haystack.as_bytes().get(start..)
+            .and_then(|tail| self.find_bytes(tail))
+            .map(|idx| start + idx)
+    }
+
+    /// Find the index of the first character in the set without any
+    /// vector instructions. A single character is searched for 8
+    /// bytes at a time; otherwise each byte is passed to the
+    /// fallback.
+    #[cfg(not(any(target_arch = "aarch64",
+                  all(target_arch = "wasm32", target_feature = "simd128"))))]
+    fn find_fallback(&self, haystack: &[u8]) -> Option<usize> {
+        let mut offset = 0;
+
+        if self.inner.len() == 1 {
+            let (words_searched, result) = find_byte_swar(self.inner.byte_at(0), haystack);
+            if result.is_some() {
+                return result;
+            }
+            offset = words_searched;
+        }
+
+        haystack[offset..].iter().cloned().position(&self.fallback).map(|idx| offset + idx)
+    }
+
+    /// Panics if the fallback disagrees with the set of characters
+    /// about any byte, which would make the results depend on the
+    /// CPU. Only checked in debug builds, and only when the fallback
+    /// or the set changes, so searching stays cheap.
+    #[inline]
+    fn debug_assert_consistent(&self) {
+        if cfg!(debug_assertions) {
+            for b in 0..256 {
+                let b = b as u8;
+                if self.inner.contains_byte(b) {
+                    assert!((self.fallback)(b),
+                            "The fallback does not match byte 0x{:02x}, which is in the set", b);
+                } else {
+                    assert!(!(self.fallback)(b),
+                            "The fallback matches byte 0x{:02x}, which is not in the set", b);
+                }
+            }
+        }
+    }
+
+    /// A copy of this searcher that searches for `needles` with the
+    /// same fallback.
+    ///
+    /// The fallback must still agree with the new set about every
+    /// byte; that is up to the caller, and is checked here in debug
+    /// builds.
+    #[must_use]
+    pub fn with_needles(&self, needles: AsciiChars) -> AsciiCharsWithFallback<F>
+        where F: Clone
+    {
+        let with_needles = AsciiCharsWithFallback {
+            inner: needles,
+            fallback: self.fallback.clone(),
+            fallback_forced: self.fallback_forced,
+        };
+        with_needles.debug_assert_consistent();
+        with_needles
+    }
+
+    /// Search for `needles` from now on, keeping the same fallback.
+    ///
+    /// As with `with_needles`, the caller must keep the fallback in
+    /// agreement with the new set.
+    pub fn set_needles(&mut self, needles: AsciiChars) {
+        self.inner = needles;
+        self.debug_assert_consistent();
+    }
+
+    /// Find the index of the last character in the set.
+    #[must_use]
+    pub fn rfind(&self, haystack: &str) -> Option<usize> {
+        self.rfind_bytes(haystack.as_bytes())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn rfind_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if self.use_sse42() {
+            UnalignedByteSliceHandler { operation: self.inner }.rfind(haystack)
+        } else {
+            haystack.iter().cloned().rposition(&self.fallback)
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn rfind_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        haystack.iter().cloned().rposition(&self.fallback)
+    }
+
+    /// Iterate over the indices of every character in the set.
+    pub fn positions<'a>(self, haystack: &'a str) -> Positions<'a, AsciiCharsWithFallback<F>> {
+        Positions::new(haystack, self)
+    }
+
+    /// Count the characters in the set.
+    #[must_use]
+    pub fn count<H>(&self, haystack: H) -> usize
+        where H: AsRef<[u8]>
+    {
+        self.count_bytes(haystack.as_ref())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn count_bytes(&self, haystack: &[u8]) -> usize {
+        if self.inner.len() == 1 && !self.fallback_forced {
+            count_byte(self.inner.byte_at(0), haystack)
+        } else if self.use_sse42() {
+            UnalignedByteSliceHandler { operation: self.inner }.count(haystack)
+        } else {
+            haystack.iter().filter(|&&b| (self.fallback)(b)).count()
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn count_bytes(&self, haystack: &[u8]) -> usize {
+        if self.inner.len() == 1 {
+            count_byte(self.inner.byte_at(0), haystack)
+        } else {
+            haystack.iter().filter(|&&b| (self.fallback)(b)).count()
+        }
+    }
+
+    /// Check if any character in the set is present.
+    #[cfg(target_arch = "x86_64")]
+    #[must_use]
+    pub fn contains<H>(&self, haystack: H) -> bool
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        if self.use_sse42() {
+            UnalignedByteSliceHandler { operation: self.inner }.contains(haystack)
+        } else {
+            haystack.iter().cloned().any(&self.fallback)
+        }
+    }
+
+    /// Check if any character in the set is present.
+    #[cfg(not(target_arch = "x86_64"))]
+    #[must_use]
+    pub fn contains<H>(&self, haystack: H) -> bool
+        where H: AsRef<[u8]>
+    {
+        haystack.as_ref().iter().cloned().any(&self.fallback)
+    }
+
+    /// Find the index of the first byte that is not in the set.
+    #[must_use]
+    pub fn find_not(&self, haystack: &str) -> Option<usize> {
+        self.find_not_bytes(haystack.as_bytes())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn find_not_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if self.use_sse42() {
+            UnalignedByteSliceHandler { operation: self.inner }.find_not(haystack)
+        } else {
+            haystack.iter().position(|&b| !(self.fallback)(b))
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn find_not_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        haystack.iter().position(|&b| !(self.fallback)(b))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn rfind_not_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if self.use_sse42() {
+            UnalignedByteSliceHandler { operation: self.inner }.rfind_not(haystack)
+        } else {
+            haystack.iter().rposition(|&b| !(self.fallback)(b))
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn rfind_not_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        haystack.iter().rposition(|&b| !(self.fallback)(b))
+    }
+}
+
+unsafe impl<F> DirectSearch for AsciiCharsWithFallback<F>
+    where F: Fn(u8) -> bool
+{
+    fn find(&self, haystack: &str) -> Option<usize> {
+        self.find_bytes(haystack.as_bytes())
+    }
+
+    fn len(&self) -> usize {
+        1
+    }
+}
+
+impl<'a, F> Pattern<'a> for AsciiCharsWithFallback<F>
+    where F: Fn(u8) -> bool
+{
+    type Searcher = DirectSearcher<'a, AsciiCharsWithFallback<F>>;
+
+    fn into_searcher(self, haystack: &'a str) -> DirectSearcher<'a, AsciiCharsWithFallback<F>> {
+        // Assert that we are searching for only ascii
+        debug_assert!(self.inner.needle & !ASCII_WORD_MASK == 0);
+        debug_assert!(self.inner.needle_hi & !ASCII_WORD_MASK == 0);
+
+        DirectSearcher {
+            haystack: haystack,
+            offset: 0,
+            direct_search: self,
+            matched_empty: false,
+        }
+    }
+}
+
+/// Searches a string for a set of ASCII characters of any size. The
+/// characters are split into groups of 16, and each group is
+/// searched in turn.
+#[cfg(feature = "std")]
+#[derive(Debug,Clone)]
+pub struct ByteSet {
+    groups: Vec<AsciiChars>,
+}
+
+#[cfg(feature = "std")]
+impl ByteSet {
+    /// Create a ByteSet that searches for each of `bytes`. Duplicate
+    /// bytes are ignored.
+    ///
+    /// ### Panics
+    ///
+    /// - If any byte is non-ASCII.
+    pub fn new(bytes: &[u8]) -> ByteSet {
+        let mut seen = [false; 128];
+        let mut groups = Vec::new();
+        let mut group = AsciiChars::new();
+
+        for &byte in bytes {
+            assert!(byte < 128, "AsciiChars can only search for ASCII bytes, not 0x{:02x}", byte);
+            if seen[byte as usize] {
+                continue;
+            }
+            seen[byte as usize] = true;
+
+            if group.len() == MAXBYTES as usize {
+                groups.push(group);
+                group = AsciiChars::new();
+            }
+            group.push(byte);
+        }
+
+        if !group.is_empty() {
+            groups.push(group);
+        }
+
+        ByteSet { groups: groups }
+    }
+
+    /// An `AsciiChars` that searches for the same characters, in the
+    /// order they were given, or an error if there are more than 16
+    /// of them.
+    pub fn try_into_ascii_chars(self) -> Result<AsciiChars, TooManyBytes> {
+        match self.groups.len() {
+            0 => Ok(AsciiChars::new()),
+            1 => Ok(self.groups[0]),
+            _ => Err(TooManyBytes { len: self.groups.iter().map(|group| group.len()).sum() }),
+        }
+    }
+
+    /// Find the index of the first character in the set.
+    #[must_use]
+    pub fn find(&self, haystack: &str) -> Option<usize> {
+        let mut found = None;
+
+        for group in &self.groups {
+            // Later groups only need to look before the best match so
+            // far; every index is an ASCII byte, so is a char boundary.
+            let end = found.unwrap_or(haystack.len());
+            if let Some(index) = group.with_generated_fallback().find(&haystack[..end]) {
+                found = Some(index);
+            }
+        }
+
+        found
+    }
+}
+
+/// Searches for characters from either of two sets at once, and
+/// reports which set the character found was in.
+#[derive(Debug,Copy,Clone)]
+pub struct TwoSets {
+    a: AsciiChars,
+    b: AsciiChars,
+    // Both sets together, if they fit in one search.
+    union: Option<AsciiChars>,
+}
+
+/// Which of the sets of a `TwoSets` a character was found in.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Which {
+    A,
+    B,
+    Both,
+}
+
+impl TwoSets {
+    /// A searcher for characters in either `a` or `b`.
+    pub fn new(a: AsciiChars, b: AsciiChars) -> TwoSets {
+        TwoSets {
+            a: a,
+            b: b,
+            union: a.try_union(b).ok(),
+        }
+    }
+
+    /// Find the index of the first character in either set, along
+    /// with which set it was in. When the sets have no more than 16
+    /// distinct characters between them, the haystack is only
+    /// searched once.
+    #[must_use]
+    pub fn find<H>(&self, haystack: H) -> Option<(usize, Which)>
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+
+        let index = match self.union {
+            Some(union) => union.find_bytes(haystack)?,
+            None => {
+                let in_a = self.a.find_bytes(haystack);
+                let end = in_a.unwrap_or(haystack.len());
+                match self.b.find_bytes(&haystack[..end]) {
+                    Some(index) => index,
+                    None => in_a?,
+                }
+            }
+        };
+
+        let byte = haystack[index];
+        let which = match (self.a.contains_byte(byte), self.b.contains_byte(byte)) {
+            (true, true) => Which::Both,
+            (true, false) => Which::A,
+            _ => Which::B,
+        };
+        Some((index, which))
+    }
+}
+
+/// Searches for either a character from a set or a substring, for
+/// example any of `<>&` or the end of a CDATA section, `]]>`.
+#[derive(Debug,Copy,Clone)]
+pub struct SetOrSubstring<'n> {
+    set: AsciiChars,
+    substring: Substring<'n>,
+}
+
+/// What a `SetOrSubstring` found.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum MatchKind {
+    /// A character from the set, which is this byte.
+    Byte(u8),
+    /// The substring.
+    Substring,
+}
+
+impl<'n> SetOrSubstring<'n> {
+    /// A searcher for characters in `set` or for `substring`, which
+    /// wins when both are found at the same index.
+    pub fn new(set: AsciiChars, substring: Substring<'n>) -> SetOrSubstring<'n> {
+        SetOrSubstring {
+            set: set,
+            substring: substring,
+        }
+    }
+
+    /// Find the index of the first character in the set or occurrence
+    /// of the substring, along with which it was. When both start at
+    /// the same index, the substring wins, as it is the longer match.
+    #[must_use]
+    pub fn find<H>(&self, haystack: H) -> Option<(usize, MatchKind)>
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        let in_set = self.set.find_bytes(haystack);
+
+        // Only a substring starting no later than the character in
+        // the set matters, so don't search any further than that.
+        let end = in_set.map_or(haystack.len(), |index| {
+            min(index + self.substring.raw.len(), haystack.len())
+        });
+        match self.substring.find_bytes(&haystack[..end]) {
+            Some(index) => Some((index, MatchKind::Substring)),
+            None => in_set.map(|index| (index, MatchKind::Byte(haystack[index]))),
+        }
+    }
+}
+
+const MAXRANGES: usize = 8;
+
+/// Searches a string for ASCII characters within a set of inclusive
+/// ranges. Up to 8 ranges may be used, and they must not overlap.
+#[derive(Debug,Copy,Clone)]
+pub struct ByteRange {
+    bounds: u64,
+    bounds_hi: u64,
+    count: u8,
+}
+
+impl ByteRange {
+    /// Create a ByteRange that searches for the characters from
+    /// `lo` to `hi`, inclusive, for each `(lo, hi)` in `ranges`.
+    ///
+    /// ### Panics
+    ///
+    /// - If there are more than 8 ranges.
+    /// - If a range ends before it starts.
+    /// - If a range includes a non-ASCII byte.
+    pub fn new(ranges: &[(u8, u8)]) -> ByteRange {
+        assert!(ranges.len() <= MAXRANGES,
+                "ByteRange can search for at most {} ranges, but {} were given",
+                MAXRANGES,
+                ranges.len());
+
+        let mut words = [0u64; 2];
+        for (index, &(lo, hi)) in ranges.iter().enumerate() {
+            assert!(lo <= hi, "The range 0x{:02x}..=0x{:02x} is empty", lo, hi);
+            assert!(hi < 128, "ByteRange can only search for ASCII bytes, not 0x{:02x}", hi);
+            let pair = (hi as u64) << 8 | lo as u64;
+            words[index / 4] |= pair << (16 * (index % 4));
+        }
+
+        ByteRange {
+            bounds: words[0],
+            bounds_hi: words[1],
+            count: 2 * ranges.len() as u8,
+        }
+    }
+
+    /// The bound at `index` of the packed words, as seen by the
+    /// PCMPxSTRx instructions.
+    #[inline]
+    fn bound_at(&self, index: usize) -> u8 {
+        if index < 8 {
+            (self.bounds >> (8 * index)) as u8
+        } else {
+            (self.bounds_hi >> (8 * (index - 8))) as u8
+        }
+    }
+
+    /// Whether `byte` is within one of the ranges. This is used when
+    /// the optimized version is not available.
+    #[inline]
+    #[must_use]
+    pub fn contains_byte(&self, byte: u8) -> bool {
+        (0..self.count as usize).step_by(2).any(|index| {
+            self.bound_at(index) <= byte && byte <= self.bound_at(index + 1)
+        })
+    }
+
+    /// Find the index of the first character within the ranges.
+    #[cfg(target_arch = "x86_64")]
+    #[must_use]
+    pub fn find(&self, haystack: &str) -> Option<usize> {
+        if has_sse42() {
+            UnalignedByteSliceHandler { operation: *self }.find(haystack.as_bytes())
+        } else {
+            haystack.as_bytes().iter().position(|&b| self.contains_byte(b))
+        }
+    }
+
+    /// Find the index of the first character within the ranges.
+    #[cfg(not(target_arch = "x86_64"))]
+    #[must_use]
+    pub fn find(&self, haystack: &str) -> Option<usize> {
+        haystack.as_bytes().iter().position(|&b| self.contains_byte(b))
+    }
+
+    /// Iterate over the indices of every character within the ranges.
+    pub fn positions<'a>(&self, haystack: &'a str) -> Positions<'a, ByteRange> {
+        Positions::new(haystack, *self)
+    }
+
+    /// Count the characters within the ranges.
+    #[cfg(target_arch = "x86_64")]
+    #[must_use]
+    pub fn count<H>(&self, haystack: H) -> usize
+        where H: AsRef<[u8]>
+    {
+        let haystack = haystack.as_ref();
+        if has_sse42() {
+            UnalignedByteSliceHandler { operation: *self }.count(haystack)
+        } else {
+            haystack.iter().filter(|&&b| self.contains_byte(b)).count()
+        }
+    }
+
+    /// Count the characters within the ranges.
+    #[cfg(not(target_arch = "x86_64"))]
+    #[must_use]
+    pub fn count<H>(&self, haystack: H) -> usize
+        where H: AsRef<[u8]>
+    {
+        haystack.as_ref().iter().filter(|&&b| self.contains_byte(b)).count()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PackedCompareOperation for ByteRange {
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn initial(&self, ptr: *const u8, offset: usize, len: usize) -> u64 {
+        let needle = _mm_set_epi64x(self.bounds_hi as i64, self.bounds as i64);
+        let haystack = _mm_loadu_si128(ptr as *const __m128i);
+
+        let matching_bytes = _mm_cmpestrm::<RANGES>(needle,
+                                                    self.count as i32,
+                                                    haystack,
+                                                    min(offset + len, 16) as i32); // saturates at 16
+
+        _mm_cvtsi128_si64(matching_bytes) as u64
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn body(&self, ptr: *const u8, offset: usize, len: usize) -> u32 {
+        let needle = _mm_set_epi64x(self.bounds_hi as i64, self.bounds as i64);
+        let haystack = _mm_loadu_si128(ptr.offset(offset as isize) as *const __m128i);
+
+        _mm_cmpestri::<RANGES>(needle,
+                               self.count as i32,       // needle length
+                               haystack,
+                               min(len, 16) as i32) as u32 // haystack length
+    }
+}
+
+unsafe impl DirectSearch for ByteRange {
+    fn find(&self, haystack: &str) -> Option<usize> {
+        ByteRange::find(self, haystack)
+    }
+
+    fn len(&self) -> usize {
+        1
+    }
+}
+
+impl<'a> Pattern<'a> for ByteRange {
+    type Searcher = DirectSearcher<'a, ByteRange>;
+
+    fn into_searcher(self, haystack: &'a str) -> DirectSearcher<'a, ByteRange> {
+        DirectSearcher {
+            haystack: haystack,
+            offset: 0,
+            direct_search: self,
+            matched_empty: false,
+        }
+    }
+}
+
+/// Search a string for a substring.
+#[derive(Debug,Copy,Clone)]
+pub struct Substring<'a> {
+    raw: &'a str,
+    needle_lo: u64,
+    needle_hi: u64,
+    needle_len: u8,
+    case_insensitive: bool,
+}
+
+impl<'a> Substring<'a> {
+    /// A searcher for `needle`. An empty needle is found at the start
+    /// of any haystack, so `find` returns `Some(0)`, and the iterators
+    /// match it at every character boundary, including the end. Use
+    /// `try_new` to refuse empty needles instead.
+    pub fn new(needle: &'a str) -> Substring<'a> {
+        fn pack_needle_bytes(bytes: &[u8]) -> u64 {
+            let mut needle = 0;
+            for &b in bytes.iter().rev() {
+                needle <<= 8;
+                needle |= b as u64;
+            }
+            needle
+        }
+
+        let mut bytes = needle.as_bytes().chunks(8);
+        let needle_lo = bytes.next().map(pack_needle_bytes).unwrap_or(0);
+        let needle_hi = bytes.next().map(pack_needle_bytes).unwrap_or(0);
+
+        Substring {
+            raw: needle,
+            needle_lo: needle_lo,
+            needle_hi: needle_hi,
+            needle_len: min(needle.len(), 16) as u8,
+            case_insensitive: false,
+        }
+    }
+
+    /// Like `new`, but returns an error for an empty needle, which
+    /// would match everywhere.
+    pub fn try_new(needle: &'a str) -> Result<Substring<'a>, EmptyNeedle> {
+        if needle.is_empty() {
+            return Err(EmptyNeedle);
+        }
+        Ok(Substring::new(needle))
+    }
+
+    /// Like `new`, but ASCII letters match without regard to case.
+    /// All other bytes must match exactly.
+    pub fn new_ascii_case_insensitive(needle: &'a str) -> Substring<'a> {
+        Substring { case_insensitive: true, ..Substring::new(needle) }
+    }
+
+    /// A searcher for the first byte of the needle, in either case if
+    /// the search is case-insensitive. The needle must not be empty.
+    #[inline]
+    fn first_byte_searcher(&self) -> AsciiCharsWithFallback<impl Fn(u8) -> bool> {
+        let first = self.raw.as_bytes()[0];
+        let (lower, upper) = (first.to_ascii_lowercase(), first.to_ascii_uppercase());
+
+        if !self.case_insensitive || lower == upper {
+            memchr_searcher(first as u64, 1)
+        } else {
+            memchr_searcher((upper as u64) << 8 | lower as u64, 2)
+        }
+    }
+
+    /// Whether the needle matches at the start of `haystack`.
+    #[inline]
+    fn is_prefix_of_bytes(&self, haystack: &[u8]) -> bool {
+        let needle = self.raw.as_bytes();
+        if self.case_insensitive {
+            haystack.get(..needle.len()).map_or(false, |h| h.eq_ignore_ascii_case(needle))
+        } else {
+            haystack.starts_with(needle)
+        }
+    }
+
+    // The PCMPESTRx instructions can only compare bytes exactly, so
+    // when we can't use them we find each candidate by its first byte
+    // and double-check the rest of the needle.
+    fn find_by_first_byte(&self, haystack: &[u8]) -> Option<usize> {
+        if self.raw.is_empty() {
+            return Some(0);
+        }
+
+        let candidates = self.first_byte_searcher();
+        let mut offset = 0;
+
+        while let Some(pos) = candidates.find_bytes(&haystack[offset..]) {
+            if self.is_prefix_of_bytes(&haystack[offset + pos..]) {
+                return Some(offset + pos);
+            }
+            offset += pos + 1;
+        }
+        None
+    }
+
+    fn rfind_by_first_byte(&self, haystack: &[u8]) -> Option<usize> {
+        if self.raw.is_empty() {
+            return Some(haystack.len());
+        }
+
+        let candidates = self.first_byte_searcher();
+        let mut end = haystack.len();
+
+        while let Some(pos) = candidates.rfind_bytes(&haystack[..end]) {
+            if self.is_prefix_of_bytes(&haystack[pos..]) {
+                return Some(pos);
+            }
+            end = pos;
+        }
+        None
+    }
+
+    /// Find the index of the first occurrence of the substring in a
+    /// byte slice, which doesn't have to be on a character boundary.
+    #[cfg(target_arch = "x86_64")]
+    fn find_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if !self.case_insensitive && has_sse42() {
+            self.find_packed(haystack)
+        } else {
+            self.find_by_first_byte(haystack)
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn find_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        self.find_by_first_byte(haystack)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn find_packed(&self, haystack: &[u8]) -> Option<usize> {
+        // It's ok to treat the haystack as a bag of bytes because the
+        // needle is guaranteed to only match complete UTF-8
+        // characters. Whenever a match is found, we double-check the
+        // match position with the complete needle.
+
+        let needle = self.raw.as_bytes();
+
+        if needle.len() == 0 && haystack.len() == 0 {
+            return Some(0);
+        }
+
+        let searcher = UnalignedByteSliceHandler { operation: *self };
+        let mut offset = 0;
+
+        while let Some(pos) = searcher.find(&haystack[offset..]) {
+            // Found a match, but is it really?
+            if haystack[pos + offset..].starts_with(needle) {
+                return Some(offset + pos);
+            }
+
+            // Skip past this false positive
+            offset += pos + 1;
+        }
+        None
+    }
+
+    /// Read from `reader` until the substring is found, consuming
+    /// everything up to and including it. Returns the index of the
+    /// start of the match from where the reader was, or `None` if
+    /// the reader ran out first. Matches may straddle the chunks
+    /// returned by `fill_buf`.
+    #[cfg(feature = "std")]
+    pub fn find_in_reader<R>(&self, reader: &mut R) -> io::Result<Option<usize>>
+        where R: BufRead
+    {
+        let needle_len = self.raw.len();
+        if needle_len == 0 {
+            return Ok(Some(0));
+        }
+
+        // The last bytes of the previous chunks, in case the needle
+        // starts in them and ends in the next chunk.
+        let mut carried = Vec::with_capacity(2 * needle_len);
+        let mut offset = 0;
+
+        loop {
+            let (consumed, found) = {
+                let chunk = match reader.fill_buf() {
+                    Ok(chunk) => chunk,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+
+                if chunk.is_empty() {
+                    return Ok(None);
+                }
+
+                let carried_len = carried.len();
+                carried.extend_from_slice(&chunk[..min(chunk.len(), needle_len - 1)]);
+
+                if let Some(pos) = self.find_bytes(&carried) {
+                    (pos + needle_len - carried_len, Some(offset - carried_len + pos))
+                } else {
+                    carried.truncate(carried_len);
+
+                    match self.find_bytes(chunk) {
+                        Some(pos) => (pos + needle_len, Some(offset + pos)),
+                        None => {
+                            carried.extend_from_slice(chunk);
+                            let excess = carried.len().saturating_sub(needle_len - 1);
+                            carried.drain(..excess);
+                            (chunk.len(), None)
+                        }
+                    }
+                }
+            };
+
+            reader.consume(consumed);
+            if found.is_some() {
+                return Ok(found);
+            }
+            offset += consumed;
+        }
+    }
+
+    /// Find the index of the first occurrence of the substring in the
+    /// bytes of `chunks` one after the other, as if they had been
+    /// concatenated. Matches may straddle any number of chunks.
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub fn find_in_chunks<'c, I>(&self, chunks: I) -> Option<usize>
+        where I: IntoIterator<Item = &'c [u8]>
+    {
+        let needle_len = self.raw.len();
+        if needle_len == 0 {
+            return Some(0);
+        }
+
+        // The last bytes of the previous chunks, in case the needle
+        // starts in them and ends in the next chunk.
+        let mut carried = Vec::with_capacity(2 * needle_len);
+        let mut offset = 0;
+
+        for chunk in chunks {
+            let carried_len = carried.len();
+            carried.extend_from_slice(&chunk[..min(chunk.len(), needle_len - 1)]);
+
+            if let Some(pos) = self.find_bytes(&carried) {
+                return Some(offset - carried_len + pos);
+            }
+            carried.truncate(carried_len);
+
+            if let Some(pos) = self.find_bytes(chunk) {
+                return Some(offset + pos);
+            }
+
+            carried.extend_from_slice(chunk);
+            let excess = carried.len().saturating_sub(needle_len - 1);
+            carried.drain(..excess);
+            offset += chunk.len();
+        }
+        None
+    }
+
+    /// Iterate over the indices of the matches that don't overlap,
+    /// like `str::match_indices`. An empty substring matches at every
+    /// character boundary, including the end of the haystack.
+    ///
+    /// From the back, matches are found like `str::rmatch_indices`,
+    /// which can pick different matches when they overlap. Whichever
+    /// end a match comes from, it never overlaps one from the other.
+    pub fn find_iter<'h>(&self, haystack: &'h str) -> FindIter<'h, 'a> {
+        FindIter {
+            haystack: haystack,
+            offset: 0,
+            end: haystack.len() + 1,
+            substring: *self,
+            overlapping: false,
+        }
+    }
+
+    /// Iterate over the ranges of the matches that don't overlap, for
+    /// slicing them out of the haystack. An empty substring gives an
+    /// empty range at every character boundary.
+    pub fn find_ranges<'h>(&self, haystack: &'h str) -> FindRanges<'h, 'a> {
+        FindRanges { inner: self.find_iter(haystack) }
+    }
+
+    /// Iterate over the indices of every match, including those that
+    /// overlap an earlier match. An empty substring matches at every
+    /// character boundary, including the end of the haystack.
+    pub fn find_overlapping_iter<'h>(&self, haystack: &'h str) -> FindIter<'h, 'a> {
+        FindIter {
+            haystack: haystack,
+            offset: 0,
+            end: haystack.len() + 1,
+            substring: *self,
+            overlapping: true,
+        }
+    }
+
+    /// Find the index of the first occurrence of the substring in a
+    /// `BStr`. The index counts bytes and doesn't have to be on a
+    /// character boundary.
+    #[cfg(feature = "bstr")]
+    #[must_use]
+    pub fn find_bstr(&self, haystack: &BStr) -> Option<usize> {
+        self.find_bytes(haystack)
+    }
+
+    /// Iterate over the indices of the matches in a `BStr` that don't
+    /// overlap. An empty substring matches at every byte, including
+    /// the end of the haystack.
+    #[cfg(feature = "bstr")]
+    pub fn find_iter_bstr<'h>(&self, haystack: &'h BStr) -> BStrFindIter<'h, 'a> {
+        BStrFindIter {
+            haystack: haystack,
+            offset: 0,
+            substring: *self,
+        }
+    }
+
+    /// Find the index of the start of the last occurrence of the
+    /// substring. Like `str::rfind`, an empty substring is found at
+    /// the end of the haystack, even when the haystack is empty.
+    #[cfg(target_arch = "x86_64")]
+    #[must_use]
+    pub fn rfind(&self, haystack: &str) -> Option<usize> {
+        if self.case_insensitive {
+            return self.rfind_by_first_byte(haystack.as_bytes());
+        }
+
+        if !has_sse42() {
+            return haystack.rfind(self.raw);
+        }
+
+        self.rfind_packed(haystack.as_bytes())
+    }
+
+    /// Find the index of the start of the last occurrence of the
+    /// substring in a byte slice, which doesn't have to be on a
+    /// character boundary.
+    #[cfg(target_arch = "x86_64")]
+    fn rfind_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        if !self.case_insensitive && has_sse42() {
+            self.rfind_packed(haystack)
+        } else {
+            self.rfind_by_first_byte(haystack)
+        }
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn rfind_bytes(&self, haystack: &[u8]) -> Option<usize> {
+        self.rfind_by_first_byte(haystack)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn rfind_packed(&self, haystack: &[u8]) -> Option<usize> {
+        let needle = self.raw.as_bytes();
+
+        if needle.len() == 0 {
+            return Some(haystack.len());
+        }
+
+        // Each candidate only has to start before the end of the
+        // bytes we're searching; the bytes after it are still there
+        // for the PCMPESTRM instruction and the double-check to see.
+
+        let searcher = UnalignedByteSliceHandler { operation: *self };
+        let mut end = haystack.len();
+
+        while let Some(pos) = searcher.rfind(&haystack[..end]) {
+            // Found a match, but is it really?
+            if haystack[pos..].starts_with(needle) {
+                return Some(pos);
+            }
+
+            // Skip before this false positive
+            end = pos;
+        }
+        None
+    }
+
+    /// Find the index of the start of the last occurrence of the
+    /// substring. Like `str::rfind`, an empty substring is found at
+    /// the end of the haystack, even when the haystack is empty.
+    #[cfg(not(target_arch = "x86_64"))]
+    #[must_use]
+    pub fn rfind(&self, haystack: &str) -> Option<usize> {
+        if self.case_insensitive {
+            return self.rfind_by_first_byte(haystack.as_bytes());
+        }
+
+        haystack.rfind(self.raw)
+    }
+
+    /// Find the index of the first occurrence of the substring that is
+    /// a whole word: the bytes just before and after it must not be
+    /// word bytes according to `is_word`. The start and end of the
+    /// haystack count as boundaries.
+    #[must_use]
+    pub fn find_word<F>(&self, haystack: &[u8], is_word: F) -> Option<usize>
+        where F: Fn(u8) -> bool
+    {
+        let mut offset = 0;
+
+        while offset <= haystack.len() {
+            let pos = offset + self.find_bytes(&haystack[offset..])?;
+            let end = pos + self.raw.len();
+
+            let bounded_before = pos == 0 || !is_word(haystack[pos - 1]);
+            let bounded_after = end == haystack.len() || !is_word(haystack[end]);
+            if bounded_before && bounded_after {
+                return Some(pos);
+            }
+
+            // Skip past this match that's inside a word
+            offset = pos + 1;
+        }
+        None
+    }
+
+    /// Split `haystack` around the first occurrence of the substring,
+    /// like `str::split_once`. The substring isn't in either part.
+    #[must_use]
+    pub fn split_once<'h>(&self, haystack: &'h str) -> Option<(&'h str, &'h str)> {
+        let start = self.find(haystack)?;
+        Some((&haystack[..start], &haystack[start + self.raw.len()..]))
+    }
+
+    /// Like `split_once`, but for a byte slice.
+    #[must_use]
+    pub fn split_once_bytes<'h>(&self, haystack: &'h [u8]) -> Option<(&'h [u8], &'h [u8])> {
+        let start = self.find_bytes(haystack)?;
+        Some((&haystack[..start], &haystack[start + self.raw.len()..]))
+    }
+
+    /// The rest of `haystack` if it starts with the substring, like
+    /// `[u8]::strip_prefix`.
+    #[must_use]
+    pub fn strip_prefix<'h>(&self, haystack: &'h [u8]) -> Option<&'h [u8]> {
+        if self.is_prefix_of_bytes(haystack) {
+            Some(&haystack[self.raw.len()..])
+        } else {
+            None
+        }
+    }
+
+    /// The start of `haystack` if it ends with the substring, like
+    /// `[u8]::strip_suffix`.
+    #[must_use]
+    pub fn strip_suffix<'h>(&self, haystack: &'h [u8]) -> Option<&'h [u8]> {
+        let split = haystack.len().checked_sub(self.raw.len())?;
+        if self.is_prefix_of_bytes(&haystack[split..]) {
+            Some(&haystack[..split])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl<'a> PackedCompareOperation for Substring<'a> {
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn initial(&self, haystack: *const u8, offset: usize, len: usize) -> u64 {
+        let needle = _mm_set_epi64x(self.needle_hi as i64, self.needle_lo as i64);
+        let haystack = _mm_loadu_si128(haystack as *const __m128i);
+
+        let matching_bytes = _mm_cmpestrm::<EQUAL_ORDERED>(needle,
+                                                           self.needle_len as i32,
+                                                           haystack,
+                                                           min(offset + len, 16) as i32);
+
+        _mm_cvtsi128_si64(matching_bytes) as u64
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn body(&self, haystack: *const u8, offset: usize, len: usize) -> u32 {
+        let needle = _mm_set_epi64x(self.needle_hi as i64, self.needle_lo as i64);
+        let haystack = _mm_loadu_si128(haystack.offset(offset as isize) as *const __m128i);
+
+        _mm_cmpestri::<EQUAL_ORDERED>(needle,
+                                      self.needle_len as i32,
+                                      haystack,
+                                      min(len, 16) as i32) as u32
+    }
+}
+
+unsafe impl<'a> DirectSearch for Substring<'a> {
+    #[cfg(target_arch = "x86_64")]
+    fn find(&self, haystack: &str) -> Option<usize> {
+        if self.case_insensitive {
+            return self.find_by_first_byte(haystack.as_bytes());
+        }
+
+        if !has_sse42() {
+            return haystack.find(self.raw);
+        }
+
+        self.find_packed(haystack.as_bytes())
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn find(&self, haystack: &str) -> Option<usize> {
+        if self.case_insensitive {
+            return self.find_by_first_byte(haystack.as_bytes());
+        }
+
+        haystack.find(self.raw)
+    }
+
+    fn len(&self) -> usize {
+        self.raw.len()
+    }
+}
+
+impl<'a> Pattern<'a> for Substring<'a> {
+    type Searcher = DirectSearcher<'a, Substring<'a>>;
+
+    fn into_searcher(self, haystack: &'a str) -> DirectSearcher<'a, Substring<'a>> {
+        DirectSearcher {
+            haystack: haystack,
+            offset: 0,
+            direct_search: self,
+            matched_empty: false,
+        }
+    }
+}
+
+/// Search a string for the first of several substrings.
+#[cfg(feature = "std")]
+#[derive(Debug,Clone)]
+pub struct SubstringSet<'a> {
+    substrings: Vec<Substring<'a>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a> SubstringSet<'a> {
+    /// A searcher for any of `needles`, numbered in the order given.
+    pub fn new(needles: &[&'a str]) -> SubstringSet<'a> {
+        SubstringSet { substrings: needles.iter().map(|needle| Substring::new(needle)).collect() }
+    }
+
+    /// Find the earliest match of any of the substrings. Returns the
+    /// index of the match along with the index of the needle that
+    /// matched; when several needles match at the same index, the
+    /// first of them is used.
+    #[must_use]
+    pub fn find(&self, haystack: &str) -> Option<(usize, usize)> {
+        let mut found: Option<(usize, usize)> = None;
+
+        for (needle_index, substring) in self.substrings.iter().enumerate() {
+            if let Some(index) = substring.find(haystack) {
+                if found.map_or(true, |(best, _)| index < best) {
+                    found = Some((index, needle_index));
+                }
+            }
+        }
+
+        found
+    }
+}
+
+/// Assembles the needle of a `Substring` piece by piece. The searcher
+/// it builds borrows the needle from the builder.
+#[cfg(feature = "std")]
+#[derive(Debug,Clone,Default)]
+pub struct SubstringBuilder {
+    needle: String,
+}
+
+#[cfg(feature = "std")]
+impl SubstringBuilder {
+    /// A builder with an empty needle.
+    pub fn new() -> SubstringBuilder {
+        SubstringBuilder::default()
+    }
+
+    /// Add `s` to the end of the needle.
+    pub fn push_str(&mut self, s: &str) {
+        self.needle.push_str(s);
+    }
+
+    /// Add an ASCII byte to the end of the needle.
+    ///
+    /// ### Panics
+    ///
+    /// - If you add a non-ASCII byte, which would leave the needle
+    ///   as invalid UTF-8.
+    pub fn push_byte(&mut self, byte: u8) {
+        assert!(byte < 128, "Substring can only be built from ASCII bytes, not 0x{:02x}", byte);
+        self.needle.push(byte as char);
+    }
+
+    /// A searcher for the needle so far, which searches exactly as
+    /// `Substring::new` would for the same string. An empty needle is
+    /// an error, as with `Substring::try_new`.
+    pub fn build(&self) -> Result<Substring<'_>, EmptyNeedle> {
+        Substring::try_new(&self.needle)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Extend<&'a str> for SubstringBuilder {
+    fn extend<I>(&mut self, iter: I)
+        where I: IntoIterator<Item = &'a str>
+    {
+        for s in iter {
+            self.push_str(s);
+        }
+    }
+}
+
+/// An iterator over the indices of the matches of a substring.
+#[derive(Debug,Copy,Clone)]
+pub struct FindIter<'h, 'n> {
+    haystack: &'h str,
+    offset: usize,
+    // Every match still to come starts before this.
+    end: usize,
+    substring: Substring<'n>,
+    overlapping: bool,
+}
+
+impl<'h, 'n> Iterator for FindIter<'h, 'n> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        if self.offset > self.haystack.len() {
+            return None;
+        }
+
+        let pos = match self.substring.find(&self.haystack[self.offset..]) {
+            Some(idx) if self.offset + idx < self.end => self.offset + idx,
+            _ => {
+                self.offset = self.haystack.len() + 1;
+                return None;
+            }
+        };
+
+        // The next match has to start on a character boundary, so
+        // overlapping matches skip the needle's first character.
+        let needle = self.substring.raw;
+        let step = if needle.is_empty() {
+            self.haystack[pos..].chars().next().map_or(1, char::len_utf8)
+        } else if self.overlapping {
+            needle.chars().next().map_or(1, char::len_utf8)
+        } else {
+            needle.len()
+        };
+
+        self.offset = pos + step;
+        Some(pos)
+    }
+}
+
+impl<'h, 'n> DoubleEndedIterator for FindIter<'h, 'n> {
+    #[inline]
+    fn next_back(&mut self) -> Option<usize> {
+        if self.offset >= self.end {
+            return None;
+        }
+
+        let needle_len = self.substring.raw.len();
+        let last_start = self.end - 1;
+
+        let pos = if needle_len == 0 {
+            // The offset is a character boundary, so this stops there
+            let mut pos = last_start;
+            while !self.haystack.is_char_boundary(pos) {
+                pos -= 1;
+            }
+            Some(pos)
+        } else {
+            // A match starting at `last_start` may run past it
+            let limit = min(last_start + needle_len, self.haystack.len());
+            let haystack = &self.haystack.as_bytes()[self.offset..limit];
+            self.substring.rfind_bytes(haystack).map(|idx| self.offset + idx)
+        };
+
+        match pos {
+            Some(pos) => {
+                // Matches that don't overlap have to end before this
+                // one starts.
+                self.end = if self.overlapping || needle_len == 0 {
+                    pos
+                } else {
+                    (pos + 1).saturating_sub(needle_len)
+                };
+                Some(pos)
+            }
+            None => {
+                self.end = 0;
+                None
+            }
+        }
+    }
+}
+
+impl<'h, 'n> FusedIterator for FindIter<'h, 'n> {}
+
+/// An iterator over the ranges of the matches of a substring.
+#[derive(Debug,Copy,Clone)]
+pub struct FindRanges<'h, 'n> {
+    inner: FindIter<'h, 'n>,
+}
+
+impl<'h, 'n> Iterator for FindRanges<'h, 'n> {
+    type Item = Range<usize>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Range<usize>> {
+        let len = self.inner.substring.raw.len();
+        self.inner.next().map(|start| start..start + len)
+    }
+}
+
+impl<'h, 'n> DoubleEndedIterator for FindRanges<'h, 'n> {
+    #[inline]
+    fn next_back(&mut self) -> Option<Range<usize>> {
+        let len = self.inner.substring.raw.len();
+        self.inner.next_back().map(|start| start..start + len)
+    }
+}
+
+impl<'h, 'n> FusedIterator for FindRanges<'h, 'n> {}
+
+/// An iterator over the byte indices of the matches of a substring
+/// in a `BStr`.
+#[cfg(feature = "bstr")]
+#[derive(Debug,Copy,Clone)]
+pub struct BStrFindIter<'h, 'n> {
+    haystack: &'h BStr,
+    offset: usize,
+    substring: Substring<'n>,
+}
+
+#[cfg(feature = "bstr")]
+impl<'h, 'n> Iterator for BStrFindIter<'h, 'n> {
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        if self.offset > self.haystack.len() {
+            return None;
+        }
+
+        match self.substring.find_bytes(&self.haystack[self.offset..]) {
+            Some(idx) => {
+                // Byte strings have no character boundaries to keep
+                // to, so an empty needle just moves on a byte.
+                let pos = self.offset + idx;
+                let len = self.substring.raw.len();
+                self.offset = pos + if len == 0 { 1 } else { len };
+                Some(pos)
+            }
+            None => {
+                self.offset = self.haystack.len() + 1;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "bstr")]
+impl<'h, 'n> FusedIterator for BStrFindIter<'h, 'n> {}
+
+/// Types that return the index of the next match.
+// Do we really want to expose the trait like this?
+pub unsafe trait DirectSearch {
+    #[must_use]
+    fn find(&self, haystack: &str) -> Option<usize>;
+    fn len(&self) -> usize;
+}
+
+/// A searcher implementation for DirectSearch types.
+#[derive(Debug,Copy,Clone)]
+pub struct DirectSearcher<'a, D> {
+    haystack: &'a str,
+    offset: usize,
+    direct_search: D,
+    // Whether an empty needle has matched at the offset, so the next
+    // step moves past the character there.
+    matched_empty: bool,
+}
+
+unsafe impl<'a, D> Searcher<'a> for DirectSearcher<'a, D>
+    where D: DirectSearch
+{
+    fn haystack(&self) -> &'a str {
+        self.haystack
+    }
+
+    #[inline]
+    fn next(&mut self) -> SearchStep {
+        if self.direct_search.len() == 0 {
+            return self.next_empty();
+        }
+
+        if self.offset >= self.haystack.len() {
+            return SearchStep::Done;
+        }
+
+        let left_to_search = &self.haystack[self.offset..]; // TODO: unchecked_slice?
+        let idx = self.direct_search.find(left_to_search);
+
+        // If there's no match, then the rest of the string should be
+        // returned.
+        let idx = idx.unwrap_or(self.haystack.len());
+
+        let (res, next_offset) = if idx == 0 {
+            // A match occurs at the beginning of the string
+            let next = self.offset + self.direct_search.len();
+            (SearchStep::Match(self.offset, next), next)
+        } else {
+            // A match occurs somewhere further in the string
+            let next = self.offset + idx;
+            (SearchStep::Reject(self.offset, next), next)
+        };
+
+        self.offset = next_offset;
+        res
+    }
+}
+
+impl<'a, D> DirectSearcher<'a, D> {
+    // An empty needle matches at every character boundary, including
+    // the end, like `str::split("")`. Each character in between is
+    // rejected so the searcher always makes progress.
+    fn next_empty(&mut self) -> SearchStep {
+        if self.offset > self.haystack.len() {
+            return SearchStep::Done;
+        }
+
+        if !self.matched_empty {
+            self.matched_empty = true;
+            return SearchStep::Match(self.offset, self.offset);
+        }
+        self.matched_empty = false;
+
+        match self.haystack[self.offset..].chars().next() {
+            Some(c) => {
+                let start = self.offset;
+                self.offset += c.len_utf8();
+                SearchStep::Reject(start, self.offset)
+            }
+            None => {
+                self.offset += 1;
+                SearchStep::Done
+            }
+        }
+    }
+}
+
+/// An iterator over the indices of every match in a string.
+#[derive(Debug,Copy,Clone)]
+pub struct Positions<'a, D> {
+    haystack: &'a str,
+    offset: usize,
+    end: usize,
+    direct_search: D,
+    // A match found by `peek`; the offset is already past it.
+    peeked: Option<usize>,
+}
+
+impl<'a, D> Positions<'a, D> {
+    fn new(haystack: &'a str, direct_search: D) -> Positions<'a, D> {
+        Positions {
+            haystack: haystack,
+            offset: 0,
+            end: haystack.len(),
+            direct_search: direct_search,
+            peeked: None,
+        }
+    }
+
+    /// Search backwards for the last match that hasn't been yielded.
+    #[inline]
+    fn next_back_with<R>(&mut self, rfind: R) -> Option<usize>
+        where R: FnOnce(&D, &[u8]) -> Option<usize>
+    {
+        let left_to_search = &self.haystack.as_bytes()[self.offset..self.end];
+
+        match rfind(&self.direct_search, left_to_search) {
+            Some(idx) => {
+                // Resume the search directly before this match
+                let found = self.offset + idx;
+                self.end = found;
+                Some(found)
+            }
+            None => {
+                // Nothing is left to match, so stay exhausted. A
+                // peeked match comes before everything else.
+                self.end = self.offset;
+                self.peeked.take()
+            }
+        }
+    }
+}
+
+impl<'a, D> Positions<'a, D>
+    where D: DirectSearch
+{
+    /// The index of the next match, without consuming it. The match
+    /// is remembered, so the following `next` doesn't search again.
+    #[inline]
+    pub fn peek(&mut self) -> Option<usize> {
+        if self.peeked.is_none() {
+            self.peeked = self.search_forwards();
+        }
+        self.peeked
+    }
+
+    #[inline]
+    fn search_forwards(&mut self) -> Option<usize> {
+        let left_to_search = &self.haystack[self.offset..self.end];
+
+        match self.direct_search.find(left_to_search) {
+            Some(idx) => {
+                // Resume the search directly after this match
+                let found = self.offset + idx;
+                self.offset = found + 1;
+                Some(found)
+            }
+            None => {
+                // Nothing is left to match, so stay exhausted
+                self.offset = self.end;
+                None
+            }
+        }
+    }
+}
+
+impl<'a, D> Iterator for Positions<'a, D>
+    where D: DirectSearch
+{
+    type Item = usize;
+
+    #[inline]
+    fn next(&mut self) -> Option<usize> {
+        match self.peeked.take() {
+            Some(found) => Some(found),
+            None => self.search_forwards(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Positions<'a, AsciiChars> {
+    #[inline]
+    fn next_back(&mut self) -> Option<usize> {
+        self.next_back_with(|chars, haystack| chars.rfind_bytes(haystack))
+    }
+}
+
+impl<'a, F> DoubleEndedIterator for Positions<'a, AsciiCharsWithFallback<F>>
+    where F: Fn(u8) -> bool
+{
+    #[inline]
+    fn next_back(&mut self) -> Option<usize> {
+        self.next_back_with(|chars, haystack| chars.rfind_bytes(haystack))
+    }
+}
+
+impl<'a, D> FusedIterator for Positions<'a, D> where D: DirectSearch {}
+
+/// An iterator over the parts of a byte slice separated by any of a
+/// set of characters.
+#[derive(Debug,Copy,Clone)]
+pub struct Split<'a> {
+    haystack: &'a [u8],
+    chars: AsciiChars,
+    finished: bool,
+}
+
+impl<'a> Split<'a> {
+    #[inline]
+    fn finish(&mut self) -> Option<&'a [u8]> {
+        if self.finished {
+            None
+        } else {
+            self.finished = true;
+            Some(self.haystack)
+        }
+    }
+}
+
+impl<'a> Iterator for Split<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.finished {
+            return None;
+        }
+
+        match self.chars.find_bytes(self.haystack) {
+            Some(idx) => {
+                let part = &self.haystack[..idx];
+                self.haystack = &self.haystack[idx + 1..];
+                Some(part)
+            }
+            None => self.finish(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Split<'a> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        if self.finished {
+            return None;
+        }
+
+        match self.chars.rfind_bytes(self.haystack) {
+            Some(idx) => {
+                let part = &self.haystack[idx + 1..];
+                self.haystack = &self.haystack[..idx];
+                Some(part)
+            }
+            None => self.finish(),
+        }
+    }
+}
+
+impl<'a> FusedIterator for Split<'a> {}
+
+/// An iterator over at most `n` parts of a byte slice separated by
+/// any of a set of characters.
+#[derive(Debug,Copy,Clone)]
+pub struct SplitN<'a> {
+    inner: Split<'a>,
+    count: usize,
+}
+
+impl<'a> Iterator for SplitN<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match self.count {
+            0 => None,
+            1 => {
+                self.count = 0;
+                self.inner.finish()
+            }
+            _ => {
+                self.count -= 1;
+                self.inner.next()
+            }
+        }
+    }
+}
+
+impl<'a> FusedIterator for SplitN<'a> {}
+
+/// An iterator over the parts of a byte slice separated by any of a
+/// set of characters, starting from the end.
+#[derive(Debug,Copy,Clone)]
+pub struct RSplit<'a> {
+    inner: Split<'a>,
+}
+
+impl<'a> Iterator for RSplit<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a> DoubleEndedIterator for RSplit<'a> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        self.inner.next()
+    }
+}
+
+impl<'a> FusedIterator for RSplit<'a> {}
+
+/// An iterator over at most `n` parts of a byte slice separated by
+/// any of a set of characters, starting from the end.
+#[derive(Debug,Copy,Clone)]
+pub struct RSplitN<'a> {
+    inner: Split<'a>,
+    count: usize,
+}
+
+impl<'a> Iterator for RSplitN<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match self.count {
+            0 => None,
+            1 => {
+                self.count = 0;
+                self.inner.finish()
+            }
+            _ => {
+                self.count -= 1;
+                self.inner.next_back()
+            }
+        }
+    }
+}
+
+impl<'a> FusedIterator for RSplitN<'a> {}
+
+/// An iterator over the parts of a byte slice that end with any of a
+/// set of characters.
+#[derive(Debug,Copy,Clone)]
+pub struct SplitInclusive<'a> {
+    haystack: &'a [u8],
+    chars: AsciiChars,
+}
+
+impl<'a> Iterator for SplitInclusive<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.haystack.is_empty() {
+            return None;
+        }
+
+        let end = self.chars.find_bytes(self.haystack).map_or(self.haystack.len(), |idx| idx + 1);
+        let (part, rest) = self.haystack.split_at(end);
+        self.haystack = rest;
+        Some(part)
+    }
+}
+
+impl<'a> DoubleEndedIterator for SplitInclusive<'a> {
+    #[inline]
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        if self.haystack.is_empty() {
+            return None;
+        }
+
+        // The last byte ends the last part, whether it's in the set or not
+        let before_last = &self.haystack[..self.haystack.len() - 1];
+        let start = self.chars.rfind_bytes(before_last).map_or(0, |idx| idx + 1);
+        let (rest, part) = self.haystack.split_at(start);
+        self.haystack = rest;
+        Some(part)
+    }
+}
+
+impl<'a> FusedIterator for SplitInclusive<'a> {}
+
+/// A part of a byte slice yielded by `Segments`.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Segment<'a> {
+    /// The bytes before the character that ends this part.
+    pub before: &'a [u8],
+    /// The character that ends this part, or `None` for the last part.
+    pub delimiter: Option<u8>,
+    /// The index of `delimiter` in the haystack.
+    pub at: Option<usize>,
+}
+
+/// An iterator over the parts of a byte slice separated by any of a
+/// set of characters, along with the character after each part.
+#[derive(Debug,Copy,Clone)]
+pub struct Segments<'a> {
+    haystack: &'a [u8],
+    offset: usize,
+    chars: AsciiChars,
+    finished: bool,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Segment<'a>> {
+        if self.finished {
+            return None;
+        }
+
+        let rest = &self.haystack[self.offset..];
+        match self.chars.find_bytes(rest) {
+            Some(idx) => {
+                let at = self.offset + idx;
+                self.offset = at + 1;
+                Some(Segment { before: &rest[..idx], delimiter: Some(rest[idx]), at: Some(at) })
+            }
+            None => {
+                self.finished = true;
+                Some(Segment { before: rest, delimiter: None, at: None })
+            }
+        }
+    }
+}
+
+impl<'a> FusedIterator for Segments<'a> {}
+
+/// An iterator over the index of every character in a set, along with
+/// which character it was.
+#[derive(Debug,Copy,Clone)]
+pub struct MatchIndices<'a> {
+    haystack: &'a [u8],
+    offset: usize,
+    chars: AsciiChars,
+}
+
+impl<'a> Iterator for MatchIndices<'a> {
+    type Item = (usize, u8);
+
+    #[inline]
+    fn next(&mut self) -> Option<(usize, u8)> {
+        match self.chars.find_matching(&self.haystack[self.offset..]) {
+            Some((idx, byte)) => {
+                let pos = self.offset + idx;
+                self.offset = pos + 1;
+                Some((pos, byte))
+            }
+            None => {
+                self.offset = self.haystack.len();
+                None
+            }
+        }
+    }
+}
+
+impl<'a> FusedIterator for MatchIndices<'a> {}
+
+/// A searcher for up to 8 bytes of any value, packed into one word.
+/// Unlike `AsciiChars`, non-ASCII bytes are allowed because indices
+/// into a byte slice don't need to fall on a character boundary.
+#[inline]
+fn memchr_searcher(needles: u64, count: u8) -> AsciiCharsWithFallback<impl Fn(u8) -> bool> {
+    AsciiChars { needle: needles, needle_hi: 0, count: count }.with_generated_fallback()
+}
+
+/// Find the index of the first occurrence of `needle`, like the
+/// `memchr` crate.
+#[inline]
+#[must_use]
+pub fn memchr(needle: u8, haystack: &[u8]) -> Option<usize> {
+    memchr_searcher(needle as u64, 1).find_bytes(haystack)
+}
+
+/// Find the index of the first occurrence of either needle, like the
+/// `memchr` crate.
+#[inline]
+#[must_use]
+pub fn memchr2(needle1: u8, needle2: u8, haystack: &[u8]) -> Option<usize> {
+    let needles = (needle2 as u64) << 8 | needle1 as u64;
+    memchr_searcher(needles, 2).find_bytes(haystack)
+}
+
+/// Find the index of the first occurrence of any of the needles, like
+/// the `memchr` crate.
+#[inline]
+#[must_use]
+pub fn memchr3(needle1: u8, needle2: u8, needle3: u8, haystack: &[u8]) -> Option<usize> {
+    let needles = (needle3 as u64) << 16 | (needle2 as u64) << 8 | needle1 as u64;
+    memchr_searcher(needles, 3).find_bytes(haystack)
+}
+
+/// Find the index of the last occurrence of `needle`, like the
+/// `memchr` crate.
+#[inline]
+#[must_use]
+pub fn memrchr(needle: u8, haystack: &[u8]) -> Option<usize> {
+    memchr_searcher(needle as u64, 1).rfind_bytes(haystack)
+}
+
+/// Count the occurrences of `needle`. This compares 16 bytes at a time
+/// without the PCMPxSTRx instructions, which is faster when there is
+/// only one byte to look for.
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+#[must_use]
+pub fn count_byte(needle: u8, haystack: &[u8]) -> usize {
+    use core::arch::x86_64::{_mm_cmpeq_epi8, _mm_movemask_epi8, _mm_set1_epi8};
+
+    let chunks = haystack.chunks_exact(16);
+    let tail = chunks.remainder();
+
+    let mut count = 0;
+    unsafe {
+        let needles = _mm_set1_epi8(needle as i8);
+        for chunk in chunks {
+            let bytes = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let matching_bytes = _mm_movemask_epi8(_mm_cmpeq_epi8(bytes, needles));
+            count += matching_bytes.count_ones() as usize;
+        }
+    }
+
+    count + tail.iter().filter(|&&b| b == needle).count()
+}
+
+/// Find the index of the first occurrence of `needle`, comparing 16
+/// bytes at a time like `count_byte`. SSE2 is part of every x86_64
+/// CPU.
+#[cfg(target_arch = "x86_64")]
+fn find_byte_sse2(needle: u8, haystack: &[u8]) -> Option<usize> {
+    use core::arch::x86_64::{_mm_cmpeq_epi8, _mm_movemask_epi8, _mm_set1_epi8};
+
+    let chunks = haystack.chunks_exact(16);
+    let tail = chunks.remainder();
+
+    let mut offset = 0;
+    unsafe {
+        let needles = _mm_set1_epi8(needle as i8);
+        for chunk in chunks {
+            let bytes = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let matching_bytes = _mm_movemask_epi8(_mm_cmpeq_epi8(bytes, needles));
+            if matching_bytes != 0 {
+                return Some(offset + matching_bytes.trailing_zeros() as usize);
+            }
+            offset += 16;
+        }
+    }
+
+    tail.iter().position(|&b| b == needle).map(|idx| offset + idx)
+}
+
+/// Count the occurrences of `needle`.
+#[cfg(not(all(target_arch = "x86_64", target_feature = "sse2")))]
+#[must_use]
+pub fn count_byte(needle: u8, haystack: &[u8]) -> usize {
+    haystack.iter().filter(|&&b| b == needle).count()
+}
+
+/// Panics if any search this CPU can run for `needle` disagrees with
+/// checking each byte of `haystack` in turn. These are the checks the
+/// crate's own tests use; they are only compiled for tests or with the
+/// `testing` feature, so other crates can run them too.
+#[cfg(any(test, feature = "testing"))]
+pub fn check_parity(needle: &AsciiChars, haystack: &[u8]) {
+    let needle = *needle;
+    let find = haystack.iter().position(|&b| needle.contains_byte(b));
+    let rfind = haystack.iter().rposition(|&b| needle.contains_byte(b));
+    let count = haystack.iter().filter(|&&b| needle.contains_byte(b)).count();
+
+    let with_fallback = needle.with_generated_fallback();
+    assert_eq!(find, with_fallback.find_bytes(haystack),
+               "find disagrees for {:?} in {:?}", needle, haystack);
+    assert_eq!(rfind, with_fallback.rfind_bytes(haystack),
+               "rfind disagrees for {:?} in {:?}", needle, haystack);
+    assert_eq!(count, with_fallback.count_bytes(haystack),
+               "count disagrees for {:?} in {:?}", needle, haystack);
+
+    #[cfg(not(any(target_arch = "aarch64",
+                  all(target_arch = "wasm32", target_feature = "simd128"))))]
+    assert_eq!(find, with_fallback.find_fallback(haystack),
+               "the fallback disagrees for {:?} in {:?}", needle, haystack);
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_sse42() {
+            let handler = UnalignedByteSliceHandler { operation: needle };
+            assert_eq!(find, handler.find(haystack),
+                       "the SSE 4.2 find disagrees for {:?} in {:?}", needle, haystack);
+            assert_eq!(rfind, handler.rfind(haystack),
+                       "the SSE 4.2 rfind disagrees for {:?} in {:?}", needle, haystack);
+            assert_eq!(count, handler.count(haystack),
+                       "the SSE 4.2 count disagrees for {:?} in {:?}", needle, haystack);
+        }
+
+        if has_avx2() {
+            assert_eq!(find, unsafe { needle.find_avx2(haystack) },
+                       "the AVX2 find disagrees for {:?} in {:?}", needle, haystack);
+        }
+
+        #[cfg(feature = "avx512")]
+        {
+            if has_avx512bw() {
+                assert_eq!(find, unsafe { needle.find_avx512(haystack) },
+                           "the AVX-512 find disagrees for {:?} in {:?}", needle, haystack);
+            }
+        }
+    }
+}
+
+/// A `winnow` parser that consumes the bytes before the first
+/// character in `chars` and returns them, leaving the character as
+/// the next input. It backtracks when there is no such character, so
+/// it is meant for complete input rather than `Partial` streams.
+#[cfg(feature = "winnow")]
+pub fn take_till_bytes<'i, E>(chars: AsciiChars) -> impl FnMut(&mut &'i [u8]) -> ModalResult<&'i [u8], E>
+    where E: ParserError<&'i [u8]>
+{
+    move |input: &mut &'i [u8]| match chars.find_bytes(*input) {
+        Some(idx) => Ok(input.next_slice(idx)),
+        None => Err(ErrMode::from_input(input)),
+    }
+}
+
+/// A `winnow` parser that consumes the bytes before the first
+/// occurrence of `needle` and returns them, leaving the needle as the
+/// next input. It backtracks when the needle isn't found, so it is
+/// meant for complete input rather than `Partial` streams.
+#[cfg(feature = "winnow")]
+pub fn take_until_substring<'i, 'n, E>(needle: Substring<'n>) -> impl FnMut(&mut &'i [u8]) -> ModalResult<&'i [u8], E> + 'n
+    where E: ParserError<&'i [u8]> + 'n,
+          'i: 'n
+{
+    move |input: &mut &'i [u8]| match needle.find_bytes(*input) {
+        Some(idx) => Ok(input.next_slice(idx)),
+        None => Err(ErrMode::from_input(input)),
+    }
+}
+
+/// The offset of the start of each line, for turning the indices
+/// found by a search into lines and columns.
+#[cfg(feature = "std")]
+#[derive(Debug,Clone)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+    len: usize,
+}
+
+#[cfg(feature = "std")]
+impl LineIndex {
+    /// Find every line in `haystack`. Lines end after each `\n`.
+    pub fn new(haystack: &[u8]) -> LineIndex {
+        let mut starts = Vec::with_capacity(count_byte(b'\n', haystack) + 1);
+        starts.push(0);
+
+        let mut offset = 0;
+        while let Some(idx) = memchr(b'\n', &haystack[offset..]) {
+            offset += idx + 1;
+            starts.push(offset);
+        }
+
+        LineIndex {
+            starts: starts,
+            len: haystack.len(),
+        }
+    }
+
+    /// The line and column of `offset`, both counting from zero. The
+    /// column is in bytes. An offset past the end is treated as the
+    /// end of the last line.
+    #[must_use]
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let offset = min(offset, self.len);
+        let line = match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        };
+        (line, offset - self.starts[line])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate quickcheck;
+    extern crate libc;
+    extern crate rand;
+    #[cfg(feature = "serde")]
+    extern crate serde_test;
+
+    use super::{AsciiChars, ByteRange, Substring, DirectSearch, EmptyNeedle, ScanResult,
+                TwoSets, Which, BudgetResult, Segment, AsciiCharsWithFnFallback,
+                SetOrSubstring, MatchKind};
+    #[cfg(feature = "std")]
+    use super::{ByteSet, LineIndex, SubstringBuilder, SubstringSet};
+    use self::quickcheck::{quickcheck, Arbitrary, Gen};
+    use std::str::pattern::{Pattern, Searcher, SearchStep};
+    use std::cmp;
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    use std::{slice, str, ptr};
+
+    pub const SPACE: AsciiChars = AsciiChars::from_words(0x0000000000000020, 0, 1);
+    // < > &
+    pub const XML_DELIM_3: AsciiChars = AsciiChars::from_words(0x00000000003c3e26, 0, 3);
+    // < > & ' "
+    pub const XML_DELIM_5: AsciiChars = AsciiChars::from_words(0x0000003c3e262722, 0, 5);
+
+    #[derive(Debug,Copy,Clone)]
+    struct AsciiChar(char);
+
+    impl Arbitrary for AsciiChar {
+        fn arbitrary<G>(g: &mut G) -> AsciiChar
+            where G: Gen
+        {
+            AsciiChar(g.gen_range::<u8>(0, 128) as char)
+        }
+    }
+
+    /// A set of up to the first 16 characters of `v`.
+    fn searcher_from(v: &[AsciiChar]) -> AsciiChars {
+        let n = cmp::min(super::MAXBYTES as usize, v.len());
+        v[..n].iter().map(|c| c.0 as u8).collect()
+    }
+
+    #[test]
+    fn works_as_find_does_for_single_characters() {
+        fn prop(s: String, c: AsciiChar) -> bool {
+            s.find(ascii_chars!(c.0)) == s.find(c.0)
+        }
+        quickcheck(prop as fn(String, AsciiChar) -> bool);
+    }
+
+    #[test]
+    fn works_as_find_does_for_multiple_characters() {
+        fn prop(s: String, (c1, c2, c3, c4): (AsciiChar, AsciiChar, AsciiChar, AsciiChar)) -> bool {
+            s.find(ascii_chars!(c1.0, c2.0, c3.0, c4.0)) == s.find(&[c1.0, c2.0, c3.0, c4.0][..])
+        }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar, AsciiChar, AsciiChar)) -> bool);
+    }
+
+    #[test]
+    fn works_as_find_does_for_up_to_16_characters() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let n = cmp::min(super::MAXBYTES as usize, v.len());
+            let mut searcher = AsciiChars::new();
+            let mut chars = ['\0'; 16];
+            for (index, &c) in v.iter().take(n).enumerate() {
+                searcher.push(c.0 as u8);
+                chars[index] = c.0;
+            }
+
+            let us = s.find(searcher.with_fallback(|b| {
+                chars[..n].iter().position(|&c| c == b as char).is_some()
+            }));
+
+            let find = s.find(&chars[..n]);
+
+            us == find
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn generated_fallback_works_as_find_does_for_up_to_16_characters() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let n = cmp::min(super::MAXBYTES as usize, v.len());
+            let mut searcher = AsciiChars::new();
+            let mut chars = ['\0'; 16];
+            for (index, &c) in v.iter().take(n).enumerate() {
+                searcher.push(c.0 as u8);
+                chars[index] = c.0;
+            }
+
+            s.find(searcher.with_generated_fallback()) == s.find(&chars[..n])
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn generated_fallback_only_matches_the_used_bytes() {
+        // The unused bytes of the words must not be matched
+        let searcher = AsciiChars::from_words(0x0000000000003e3c, 0x0000000000000026, 2);
+        let fallback = searcher.with_generated_fallback().fallback;
+
+        for b in 0..256 {
+            let b = b as u8;
+            assert_eq!(b == b'<' || b == b'>', fallback(b));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The fallback does not match byte 0x3e, which is in the set")]
+    fn fallback_missing_a_character_is_caught_in_debug() {
+        let searcher = AsciiChars::from_words(0x0000000000003e3c, 0, 2).with_fallback(|b| b == b'<');
+        "a>b".contains(searcher);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The fallback matches byte 0x26, which is not in the set")]
+    fn fallback_with_an_extra_character_is_caught_in_debug() {
+        let searcher = AsciiChars::from_words(0x000000000000003c, 0, 1)
+            .with_fallback(|b| b == b'<' || b == b'&');
+        let _ = searcher.count("a&b");
+    }
+
+    #[test]
+    fn with_needles_keeps_the_fallback() {
+        use std::cell::Cell;
+
+        // The fallback reads whichever byte is currently wanted
+        let wanted = Cell::new(b'<');
+        let fallback = |b: u8| b == wanted.get();
+        let mut searcher = AsciiChars::from_bytes(b"<").with_fallback(&fallback);
+        assert_eq!(Some(1), searcher.find_from("a<&", 0));
+
+        wanted.set(b'&');
+        let amp = searcher.with_needles(AsciiChars::from_bytes(b"&"));
+        assert_eq!(Some(2), amp.find_from("a<&", 0));
+
+        searcher.set_needles(AsciiChars::from_bytes(b"&"));
+        assert_eq!(Some(2), searcher.rfind("a<&"));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The fallback does not match byte 0x26, which is in the set")]
+    fn with_needles_that_disagree_with_the_fallback_is_caught_in_debug() {
+        let searcher = AsciiChars::from_bytes(b"<").with_fallback(|b| b == b'<');
+        let _ = searcher.with_needles(AsciiChars::from_bytes(b"&")).count("a&b");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The fallback matches byte 0x3c, which is not in the set")]
+    fn set_needles_that_disagree_with_the_fallback_is_caught_in_debug() {
+        let mut searcher = AsciiChars::from_bytes(b"<").with_fallback(|b| b == b'<');
+        searcher.set_needles(AsciiChars::from_bytes(b">"));
+    }
+
+    #[test]
+    fn fn_fallback_searchers_can_be_shared_from_a_static() {
+        use std::thread;
+
+        fn is_xml_delimiter(b: u8) -> bool {
+            b == b'<' || b == b'>' || b == b'&'
+        }
+        static XML: AsciiCharsWithFnFallback =
+            const_ascii_chars!('<', '>', '&').with_fn_fallback(is_xml_delimiter);
+
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&XML);
+
+        let found: Vec<_> = (0..4).map(|_| {
+            thread::spawn(|| "ab<cd".find(XML)).join().unwrap()
+        }).collect();
+        assert_eq!(vec![Some(2); 4], found);
+        assert_eq!(2, XML.count("a&b<"));
+    }
+
+    #[test]
+    fn force_fallback_searches_with_the_fallback() {
+        use std::cell::Cell;
+
+        let calls = Cell::new(0);
+        let fallback = |b: u8| {
+            calls.set(calls.get() + 1);
+            b == b'<' || b == b'>'
+        };
+        let mut searcher = AsciiChars::from_bytes(b"<>").with_fallback(&fallback);
+        searcher.force_fallback(true);
+
+        let haystack = "a fairly long haystack, so that SIMD could be used <here>";
+        let before = calls.get();
+        assert_eq!(Some(51), searcher.find_from(haystack, 0));
+        let forced_calls = calls.get() - before;
+        assert_eq!(Some(56), searcher.rfind(haystack));
+        assert_eq!(2, searcher.count(haystack));
+        assert!(searcher.contains(haystack));
+
+        assert!(forced_calls >= 52);
+
+        searcher.force_fallback(false);
+        let before = calls.get();
+        assert_eq!(Some(51), searcher.find_from(haystack, 0));
+        let unforced_calls = calls.get() - before;
+        assert_eq!(2, searcher.count(haystack));
+
+        // Debug builds also call the fallback for every byte value
+        // when checking it, but only the forced search calls it for
+        // the haystack too.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("sse4.2") {
+                assert_eq!(forced_calls, unforced_calls + 52);
+            }
+        }
+        let _ = unforced_calls;
+    }
+
+    #[test]
+    fn generated_fallback_matches_exactly_the_set() {
+        fn prop(v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let fallback = searcher.with_generated_fallback().fallback;
+
+            (0..256).all(|b| fallback(b as u8) == searcher.contains_byte(b as u8))
+        }
+        quickcheck(prop as fn(Vec<AsciiChar>) -> bool);
+
+        // memchr packs bytes that aren't ASCII
+        let fallback = super::memchr_searcher(0x00ff80, 3).fallback;
+        assert!(fallback(0x00) && fallback(0x80) && fallback(0xff));
+        assert!(!fallback(0x7f) && !fallback(0x01) && !fallback(0xfe));
+    }
+
+    #[test]
+    fn from_bytes_packs_like_from_words() {
+        let chars = AsciiChars::from_bytes(b"<>&'\"abcdefghijk");
+        let words = AsciiChars::from_words(0x6362612227263e3c, 0x6b6a696867666564, 16);
+        assert_eq!(format!("{:#?}", words), format!("{:#?}", chars));
+    }
+
+    #[test]
+    fn from_bytes_works_as_find_does_for_up_to_16_characters() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let n = cmp::min(super::MAXBYTES as usize, v.len());
+            let bytes: Vec<_> = v[..n].iter().map(|c| c.0 as u8).collect();
+            let chars: Vec<_> = v[..n].iter().map(|c| c.0).collect();
+
+            s.find(AsciiChars::from_bytes(&bytes).with_generated_fallback()) == s.find(&chars[..])
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    #[should_panic(expected = "AsciiChars can search for at most 16 bytes, but 17 were given")]
+    fn from_bytes_panics_with_more_than_16_bytes() {
+        AsciiChars::from_bytes(&[b'a'; 17]);
+    }
+
+    #[test]
+    fn try_from_bytes_fails_with_more_than_16_bytes() {
+        assert!(AsciiChars::try_from_bytes(&[b'a'; 16]).is_ok());
+        assert_eq!(Err(super::TooManyBytes { len: 17 }),
+                   AsciiChars::try_from_bytes(&[b'a'; 17]).map(|_| ()));
+    }
+
+    #[test]
+    fn try_push_rejects_non_ascii_characters() {
+        let mut searcher = AsciiChars::new();
+        assert_eq!(Ok(()), searcher.try_push('<'));
+        assert_eq!(Err(super::NotAsciiError { character: 'é' }), searcher.try_push('é'));
+        assert_eq!(vec![b'<'], searcher.into_iter().collect::<Vec<_>>());
+        assert_eq!("AsciiChars can only search for ASCII characters, not 'é'",
+                   super::NotAsciiError { character: 'é' }.to_string());
+    }
+
+    #[test]
+    fn try_from_chars_checks_every_character() {
+        assert_eq!(Ok(AsciiChars::from_bytes(b"<>&")), AsciiChars::try_from_chars(&['<', '>', '&']));
+        assert_eq!(Err(super::NotAsciiError { character: '\u{80}' }),
+                   AsciiChars::try_from_chars(&['<', '\u{80}', '&']));
+    }
+
+    #[test]
+    fn collect_works_as_find_does_for_up_to_16_characters() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let chars: Vec<_> = v[..searcher.len()].iter().map(|c| c.0).collect();
+
+            s.find(searcher.with_generated_fallback()) == s.find(&chars[..])
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn extend_adds_to_the_existing_characters() {
+        let mut searcher = AsciiChars::from_bytes(b"<");
+        searcher.extend(b">&&".iter().cloned());
+        let searcher = searcher.with_generated_fallback();
+
+        assert_eq!(Some(3), searcher.rfind("a<b&c"));
+        assert_eq!(3, searcher.count("<a>b&"));
+    }
+
+    #[test]
+    #[should_panic(expected = "AsciiChars can search for at most 16 bytes")]
+    fn extend_panics_with_more_than_16_bytes() {
+        let mut searcher = AsciiChars::from_bytes(&[b'a'; 16]);
+        searcher.extend(Some(b'b'));
+    }
+
+    #[test]
+    fn remove_moves_the_later_characters_down() {
+        let mut searcher = AsciiChars::from_bytes(b"abcdefghijklmnop");
+        assert!(searcher.remove(b'c'));
+        assert!(searcher.remove(b'p'));
+        assert!(searcher.remove(b'a'));
+        assert!(!searcher.remove(b'z'));
+
+        let expected = AsciiChars::from_bytes(b"bdefghijklmno");
+        assert_eq!(format!("{:#?}", expected), format!("{:#?}", searcher));
+    }
+
+    #[test]
+    fn remove_only_takes_the_first_occurrence() {
+        let mut searcher = AsciiChars::from_bytes(b"<&<");
+        assert!(searcher.remove(b'<'));
+
+        let searcher = searcher.with_generated_fallback();
+        assert_eq!(Some(1), searcher.rfind("a<b"));
+    }
+
+    #[test]
+    fn clear_removes_every_character() {
+        let mut searcher = AsciiChars::from_bytes(b"<>&");
+        searcher.clear();
+        assert!(!searcher.remove(b'<'));
+
+        assert_eq!(None, searcher.with_generated_fallback().rfind("<>&"));
+
+        searcher.push(b'&');
+        assert_eq!(Some(2), searcher.with_generated_fallback().rfind("<>&"));
+    }
+
+    #[test]
+    fn len_counts_the_characters() {
+        const EMPTY: AsciiChars = AsciiChars::new();
+        const EMPTY_LEN: usize = EMPTY.len();
+        assert_eq!(0, EMPTY_LEN);
+        assert!(EMPTY.is_empty());
+
+        let mut searcher = AsciiChars::from_bytes(b"<>");
+        assert_eq!(2, searcher.len());
+        assert!(!searcher.is_empty());
+
+        searcher.push(b'&');
+        assert_eq!(3, searcher.len());
+
+        // Only 16 bytes are ever used
+        assert_eq!(16, AsciiChars::from_words(0, 0, 20).len());
+    }
+
+    #[test]
+    fn contains_byte_only_checks_the_used_bytes() {
+        const XML: AsciiChars = AsciiChars::from_words(0x0000000000003e3c, 0x0000000000000026, 2);
+        const HAS_LT: bool = XML.contains_byte(b'<');
+        assert!(HAS_LT);
+        assert!(XML.contains_byte(b'>'));
+        assert!(!XML.contains_byte(b'&'));
+        assert!(!XML.contains_byte(0));
+    }
+
+    #[test]
+    fn macro_expands_ranges_in_order() {
+        let searcher = ascii_chars!(b'\n', b'\r', b'0'..=b'9', '_');
+        assert_eq!(AsciiChars::from_bytes(b"\n\r0123456789_"), searcher.inner);
+        for b in 0..=255 {
+            assert_eq!(searcher.inner.contains_byte(b), (searcher.fallback)(b), "0x{:02x}", b);
+        }
+
+        let letters = ascii_chars!('a'..='h', b'A'..=b'H');
+        assert_eq!(AsciiChars::from_bytes(b"abcdefghABCDEFGH"), letters.inner);
+        assert_eq!(Some(3), "123c".find(letters));
+    }
+
+    #[test]
+    fn macro_accepts_escaped_ascii_literals() {
+        let searcher = ascii_chars!('\x3c', '\u{3e}', b'\x26', '\x00'..='\x02');
+        assert_eq!(AsciiChars::from_bytes(b"<>&\x00\x01\x02"), searcher.inner);
+    }
+
+    #[test]
+    fn const_macro_builds_at_compile_time() {
+        const XML: AsciiChars = const_ascii_chars!('<', '>', '&');
+        static DIGITS: AsciiChars = const_ascii_chars!(b'0'..=b'9');
+
+        assert_eq!(AsciiChars::from_bytes(b"<>&"), XML);
+        assert_eq!(AsciiChars::from_bytes(b"0123456789"), DIGITS);
+        assert_eq!(Some(3), "abc>".find(XML));
+
+        let c = '&';
+        assert_eq!(AsciiChars::from_bytes(b"&"), const_ascii_chars!(c));
+    }
+
+    #[test]
+    fn builder_methods_chain() {
+        let searcher = AsciiChars::new()
+            .const_push(b'a')
+            .union(AsciiChars::from_bytes(b"<>"))
+            .ascii_case_insensitive()
+            .with_generated_fallback();
+        assert_eq!(Some(2), "xyA<".find(searcher));
+    }
+
+    #[test]
+    fn const_push_works_as_push_does() {
+        const XML: AsciiChars = AsciiChars::new().const_push(b'<').const_push(b'>').const_push(b'&');
+
+        let mut pushed = AsciiChars::new();
+        for &b in b"<>&" {
+            pushed.push(b);
+        }
+        assert_eq!(format!("{:#?}", pushed), format!("{:#?}", XML));
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 16")]
+    fn const_push_panics_with_more_than_16_bytes() {
+        let full = AsciiChars::from_bytes(b"abcdefghijklmnop");
+        let _ = full.const_push(b'q');
+    }
+
+    #[test]
+    #[should_panic(expected = "AsciiChars can only search for ASCII characters")]
+    fn macro_rejects_non_ascii_characters() {
+        // Would be 0x00 if it were truncated to a byte
+        let c = '\u{100}';
+        let _ = ascii_chars!('a', c);
+    }
+
+    #[test]
+    fn debug_shows_the_characters_in_the_set() {
+        let chars = AsciiChars::from_bytes(b"<>&' \\\n\x7f");
+        assert_eq!(r"AsciiChars { bytes: [b'<', b'>', b'&', b'\'', b' ', b'\\', 0x0a, 0x7f], count: 8 }",
+                   format!("{:?}", chars));
+        assert_eq!("AsciiChars { bytes: [], count: 0 }", format!("{:?}", AsciiChars::new()));
+
+        let words = format!("{:#?}", AsciiChars::from_bytes(b"<>"));
+        assert!(words.contains("lo: 0x0000000000003e3c,"), "{}", words);
+        assert!(words.contains("hi: 0x0000000000000000,"), "{}", words);
+    }
+
+    #[test]
+    fn default_is_empty() {
+        let searcher: AsciiChars = Default::default();
+        assert_eq!(format!("{:?}", AsciiChars::new()), format!("{:?}", searcher));
+    }
+
+    #[test]
+    fn push_adds_to_the_end() {
+        let mut searcher = AsciiChars::new();
+        for &b in b"abcdefghijklmnop" {
+            searcher.push(b);
+        }
+        let expected = AsciiChars::from_bytes(b"abcdefghijklmnop");
+        assert_eq!(format!("{:#?}", expected), format!("{:#?}", searcher));
+    }
+
+    #[test]
+    fn push_unique_skips_characters_already_present() {
+        let mut searcher = AsciiChars::from_bytes(b"<>");
+        assert!(!searcher.push_unique(b'<'));
+        assert!(searcher.push_unique(b'&'));
+        assert!(!searcher.push_unique(b'&'));
+        assert_eq!(AsciiChars::from_bytes(b"<>&"), searcher);
+
+        let mut full = AsciiChars::from_bytes(b"abcdefghijklmnop");
+        assert!(!full.push_unique(b'p'));
+    }
+
+    #[test]
+    fn from_bytes_dedup_keeps_the_first_of_each_byte() {
+        let searcher = AsciiChars::from_bytes_dedup(b"<<>&><&&");
+        assert_eq!(vec![b'<', b'>', b'&'], searcher.into_iter().collect::<Vec<_>>());
+
+        let noisy: Vec<u8> = b"abcdefghijklmnop".iter().cycle().cloned().take(100).collect();
+        assert_eq!(AsciiChars::from_bytes(b"abcdefghijklmnop"), AsciiChars::from_bytes_dedup(&noisy));
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 16")]
+    fn from_bytes_dedup_panics_with_more_than_16_distinct_bytes() {
+        AsciiChars::from_bytes_dedup(b"abcdefghijklmnopq");
+    }
+
+    #[test]
+    fn push_replaces_unused_bytes() {
+        let mut searcher = AsciiChars::from_words(0x0000000000003e3c, 0, 1);
+        searcher.push(b'&');
+        assert_eq!(vec![b'<', b'&'], searcher.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn equality_ignores_the_order_characters_were_added_in() {
+        assert_eq!(AsciiChars::from_bytes(b"<>&"), AsciiChars::from_bytes(b"&<>"));
+        assert_eq!(AsciiChars::new(), AsciiChars::from_words(0x3c, 0, 0));
+        assert!(AsciiChars::from_bytes(b"<>") != AsciiChars::from_bytes(b"<&"));
+        assert!(AsciiChars::from_bytes(b"<<") != AsciiChars::from_bytes(b"<"));
+    }
+
+    #[test]
+    fn equal_sets_hash_the_same() {
+        use std::collections::HashMap;
+
+        let mut cache = HashMap::new();
+        cache.insert(AsciiChars::from_bytes(b"<>&"), 1);
+        assert_eq!(Some(&1), cache.get(&AsciiChars::from_bytes(b"&><")));
+        assert_eq!(None, cache.get(&AsciiChars::from_bytes(b"<>")));
+    }
+
+    #[test]
+    fn into_iter_yields_the_characters_in_order() {
+        let searcher = AsciiChars::from_bytes(b"<>&'\"abcdefghijk");
+        let bytes: Vec<_> = (&searcher).into_iter().collect();
+        assert_eq!(&b"<>&'\"abcdefghijk"[..], &bytes[..]);
+        assert_eq!(16, searcher.into_iter().len());
+
+        let round_trip = AsciiChars::from_bytes(&bytes);
+        assert_eq!(format!("{:?}", searcher), format!("{:?}", round_trip));
+
+        let mut iter = AsciiChars::new().into_iter();
+        assert_eq!(0, iter.len());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn byte_set_works_as_find_does_for_any_number_of_characters() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let bytes: Vec<_> = v.iter().map(|c| c.0 as u8).collect();
+            let chars: Vec<_> = v.iter().map(|c| c.0).collect();
+
+            ByteSet::new(&bytes).find(&s) == s.find(&chars[..])
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn two_sets_find_the_first_of_either() {
+        fn prop(haystack: Vec<u8>, a: Vec<AsciiChar>, b: Vec<AsciiChar>) -> bool {
+            let a: AsciiChars = a.iter().take(16).map(|c| c.0 as u8).collect();
+            let b: AsciiChars = b.iter().take(16).map(|c| c.0 as u8).collect();
+            let expected = haystack.iter().position(|&byte| a.contains_byte(byte) || b.contains_byte(byte));
+
+            match TwoSets::new(a, b).find(&haystack) {
+                Some((index, which)) => {
+                    let byte = haystack[index];
+                    Some(index) == expected &&
+                        which == match (a.contains_byte(byte), b.contains_byte(byte)) {
+                            (true, true) => Which::Both,
+                            (true, false) => Which::A,
+                            _ => Which::B,
+                        }
+                }
+                None => expected.is_none(),
+            }
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn two_sets_report_which_set_matched() {
+        let sets = TwoSets::new(AsciiChars::from_bytes(b"\"'"), AsciiChars::from_bytes(b"\\'"));
+        assert_eq!(Some((3, Which::A)), sets.find("abc\"d\\"));
+        assert_eq!(Some((3, Which::B)), sets.find("abc\\d\""));
+        assert_eq!(Some((3, Which::Both)), sets.find("abc'd"));
+        assert_eq!(None, sets.find("abcd"));
+
+        // Too many characters to search for at once
+        let wide = TwoSets::new(AsciiChars::from_bytes(b"abcdefghijklmnop"),
+                                AsciiChars::from_bytes(b"ABCDEFGHIJKLMNOP"));
+        assert_eq!(Some((4, Which::B)), wide.find("0123P567a"));
+        assert_eq!(Some((4, Which::A)), wide.find("0123p567A"));
+        assert_eq!(None, wide.find("0123456789"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn byte_set_takes_the_first_match_of_any_group() {
+        let bytes: Vec<u8> = (0..30).map(|i| b'A' + i).collect();
+        let set = ByteSet::new(&bytes);
+
+        // 'Z' is in the second group, 'B' is in the first
+        assert_eq!(Some(20), set.find("01234567890123456789Z_B"));
+        assert_eq!(Some(20), set.find("01234567890123456789B_Z"));
+        assert_eq!(None, set.find("0123456789"));
+        assert_eq!(None, ByteSet::new(&[]).find("0123456789"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn byte_set_converts_to_and_from_ascii_chars() {
+        let chars = AsciiChars::from_bytes(b"<>&'\"");
+        let set = chars.into_byte_set();
+        assert_eq!(Some(3), set.find("abc&<"));
+        assert_eq!(Ok(chars), set.try_into_ascii_chars());
+
+        let set = AsciiChars::from_bytes(b"aba").into_byte_set();
+        assert_eq!(Ok(AsciiChars::from_bytes(b"ab")), set.try_into_ascii_chars());
+
+        assert_eq!(Ok(AsciiChars::new()), ByteSet::new(&[]).try_into_ascii_chars());
+
+        let bytes: Vec<u8> = (0..17).map(|i| b'A' + i).collect();
+        let err = ByteSet::new(&bytes).try_into_ascii_chars().unwrap_err();
+        assert_eq!("AsciiChars can search for at most 16 bytes, but 17 were given", err.to_string());
+    }
+
+    #[test]
+    fn ascii_case_insensitive_works_as_find_does_ignoring_case() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let n = cmp::min(8, v.len());
+            let searcher = AsciiChars::from_bytes(&v[..n].iter().map(|c| c.0 as u8).collect::<Vec<_>>());
+            let us = s.find(searcher.ascii_case_insensitive().with_generated_fallback());
+
+            let find = s.find(|c: char| v[..n].iter().any(|a| a.0.eq_ignore_ascii_case(&c)));
+
+            us == find
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn ascii_case_insensitive_only_adds_missing_cases() {
+        let searcher = AsciiChars::from_bytes(b"aB-b").ascii_case_insensitive();
+        assert_eq!(&b"aB-bA"[..], &searcher.into_iter().collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    #[should_panic(expected = "AsciiChars can search for at most 16 bytes")]
+    fn ascii_case_insensitive_panics_with_more_than_16_bytes() {
+        let _ = AsciiChars::from_bytes(b"abcdefghi").ascii_case_insensitive();
+    }
+
+    #[test]
+    fn byte_range_works_as_find_does_for_up_to_8_ranges() {
+        fn prop(s: String, v: Vec<(AsciiChar, AsciiChar)>) -> bool {
+            let ranges: Vec<_> = v.iter().take(8).map(|&(a, b)| {
+                let (a, b) = (a.0 as u8, b.0 as u8);
+                (cmp::min(a, b), cmp::max(a, b))
+            }).collect();
+
+            let us = ByteRange::new(&ranges).find(&s);
+            let find = s.find(|c| ranges.iter().any(|&(lo, hi)| lo as char <= c && c <= hi as char));
+
+            us == find
+        }
+        quickcheck(prop as fn(String, Vec<(AsciiChar, AsciiChar)>) -> bool);
+    }
+
+    #[test]
+    fn byte_range_finds_control_characters() {
+        let control = ByteRange::new(&[(0x00, 0x1f), (0x7f, 0x7f)]);
+        assert_eq!(Some(16), control.find("0123456789ABCDEF\t"));
+        assert_eq!(Some(3), control.find("abc\x7f"));
+        assert_eq!(None, control.find("0123456789ABCDEF ~"));
+
+        let parts: Vec<_> = "a\tb\nc".split(control).collect();
+        assert_eq!(&parts, &["a", "b", "c"]);
+    }
+
+    #[test]
+    fn byte_range_positions_and_count_work_as_checking_each_byte_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<(AsciiChar, AsciiChar)>) -> bool {
+            let ranges: Vec<_> = v.iter().take(8).map(|&(a, b)| {
+                let (a, b) = (a.0 as u8, b.0 as u8);
+                (cmp::min(a, b), cmp::max(a, b))
+            }).collect();
+            let in_range = |b: u8| ranges.iter().any(|&(lo, hi)| lo <= b && b <= hi);
+            let expected: Vec<_> = (0..haystack.len()).filter(|&i| in_range(haystack[i])).collect();
+
+            // Positions needs a str, so only the ASCII bytes are used
+            let ascii: String = haystack.iter().map(|&b| (b & 0x7f) as char).collect();
+            let expected_ascii: Vec<_> = ascii.bytes().enumerate()
+                .filter(|&(_, b)| in_range(b))
+                .map(|(i, _)| i)
+                .collect();
+
+            let searcher = ByteRange::new(&ranges);
+            searcher.count(&haystack) == expected.len() &&
+            searcher.positions(&ascii).collect::<Vec<_>>() == expected_ascii
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<(AsciiChar, AsciiChar)>) -> bool);
+    }
+
+    #[test]
+    fn byte_range_finds_every_non_printable_byte() {
+        let non_printable = ByteRange::new(&[(0x00, 0x08), (0x0e, 0x1f)]);
+        let haystack = "a\x00b\tc\x1bd\x0e\n";
+        assert_eq!(vec![1, 5, 7], non_printable.positions(haystack).collect::<Vec<_>>());
+        assert_eq!(3, non_printable.count(haystack));
+        assert_eq!(0, non_printable.count(""));
+    }
+
+    #[test]
+    #[should_panic(expected = "ByteRange can search for at most 8 ranges, but 9 were given")]
+    fn byte_range_panics_with_more_than_8_ranges() {
+        ByteRange::new(&[(b'a', b'a'); 9]);
+    }
+
+    #[test]
+    fn ascii_chars_without_a_fallback_works_as_find_does() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let chars: Vec<_> = v[..searcher.len()].iter().map(|c| c.0).collect();
+
+            s.find(searcher) == s.find(&chars[..])
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn ascii_chars_without_a_fallback_is_a_pattern() {
+        let delimiters = AsciiChars::from_bytes(b"-:");
+
+        let parts: Vec<_> = "86-J52:rev1".split(delimiters).collect();
+        assert_eq!(&parts, &["86", "J52", "rev1"]);
+
+        assert_eq!("J52:rev1", "--J52:rev1".trim_start_matches(delimiters));
+
+        // Indices are in bytes and never split a character
+        assert_eq!(Some(2), "é-ü".find(delimiters));
+        let parts: Vec<_> = "é-ü:".split(delimiters).collect();
+        assert_eq!(&parts, &["é", "ü", ""]);
+    }
+
+    #[test]
+    fn split_works_as_slice_split_does() {
+        fn prop(haystack: Vec<u8>, (c1, c2): (AsciiChar, AsciiChar), n: usize) -> bool {
+            let (c1, c2) = (c1.0 as u8, c2.0 as u8);
+            let chars = AsciiChars::from_bytes(&[c1, c2]);
+            let is_delimiter = |&b: &u8| b == c1 || b == c2;
+            let n = n % 5;
+
+            chars.split(&haystack).eq(haystack.split(is_delimiter)) &&
+            chars.splitn(&haystack, n).eq(haystack.splitn(n, is_delimiter)) &&
+            chars.rsplit(&haystack).eq(haystack.rsplit(is_delimiter)) &&
+            chars.rsplitn(&haystack, n).eq(haystack.rsplitn(n, is_delimiter)) &&
+            chars.split(&haystack).rev().eq(haystack.split(is_delimiter).rev())
+        }
+        quickcheck(prop as fn(Vec<u8>, (AsciiChar, AsciiChar), usize) -> bool);
+    }
+
+    #[test]
+    fn split_yields_empty_parts_at_the_ends() {
+        let comma = AsciiChars::from_bytes(b",");
+        let empty: &[u8] = b"";
+
+        assert_eq!(vec![empty], comma.split(b"").collect::<Vec<_>>());
+        assert_eq!(vec![&b"a"[..], &b"b"[..], empty], comma.split(b"a,b,").collect::<Vec<_>>());
+        assert_eq!(vec![&b"a"[..], &b"b,c"[..]], comma.splitn(b"a,b,c", 2).collect::<Vec<_>>());
+        assert_eq!(0, comma.splitn(b"a,b,c", 0).count());
+        assert_eq!(vec![empty, &b"b"[..], &b"a"[..]], comma.rsplit(b"a,b,").collect::<Vec<_>>());
+        assert_eq!(vec![&b"gz"[..], &b"archive.tar"[..]],
+                   AsciiChars::from_bytes(b".").rsplitn(b"archive.tar.gz", 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_inclusive_works_as_slice_split_inclusive_does() {
+        fn prop(haystack: Vec<u8>, (c1, c2): (AsciiChar, AsciiChar)) -> bool {
+            let (c1, c2) = (c1.0 as u8, c2.0 as u8);
+            let chars = AsciiChars::from_bytes(&[c1, c2]);
+            let is_delimiter = |&b: &u8| b == c1 || b == c2;
+
+            chars.split_inclusive(&haystack).eq(haystack.split_inclusive(is_delimiter)) &&
+            chars.split_inclusive(&haystack).rev().eq(haystack.split_inclusive(is_delimiter).rev())
+        }
+        quickcheck(prop as fn(Vec<u8>, (AsciiChar, AsciiChar)) -> bool);
+    }
+
+    #[test]
+    fn segments_put_the_haystack_back_together() {
+        fn prop(haystack: Vec<u8>, (c1, c2): (AsciiChar, AsciiChar)) -> bool {
+            let (c1, c2) = (c1.0 as u8, c2.0 as u8);
+            let chars = AsciiChars::from_bytes(&[c1, c2]);
+            let is_delimiter = |&b: &u8| b == c1 || b == c2;
+
+            let mut rebuilt = Vec::new();
+            for segment in chars.segments(&haystack) {
+                rebuilt.extend_from_slice(segment.before);
+                rebuilt.extend(segment.delimiter);
+                if segment.at != segment.delimiter.map(|_| rebuilt.len() - 1) {
+                    return false;
+                }
+            }
+
+            rebuilt == haystack &&
+            chars.segments(&haystack).map(|s| s.before).eq(haystack.split(is_delimiter))
+        }
+        quickcheck(prop as fn(Vec<u8>, (AsciiChar, AsciiChar)) -> bool);
+    }
+
+    #[test]
+    fn segments_end_with_the_undelimited_rest() {
+        let chars = AsciiChars::from_bytes(b"=;");
+        let mut segments = chars.segments(b"a=1;b");
+        assert_eq!(Some(Segment { before: b"a", delimiter: Some(b'='), at: Some(1) }), segments.next());
+        assert_eq!(Some(Segment { before: b"1", delimiter: Some(b';'), at: Some(3) }), segments.next());
+        assert_eq!(Some(Segment { before: b"b", delimiter: None, at: None }), segments.next());
+        assert_eq!(None, segments.next());
+
+        let empty: &[u8] = b"";
+        assert_eq!(vec![Segment { before: empty, delimiter: None, at: None }],
+                   chars.segments(b"").collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_inclusive_keeps_the_delimiters() {
+        let newline = AsciiChars::from_bytes(b"\n");
+
+        assert_eq!(0, newline.split_inclusive(b"").count());
+        assert_eq!(vec![&b"a\n"[..], &b"\n"[..], &b"b"[..]],
+                   newline.split_inclusive(b"a\n\nb").collect::<Vec<_>>());
+        assert_eq!(vec![&b"a\n"[..], &b"b\n"[..]], newline.split_inclusive(b"a\nb\n").collect::<Vec<_>>());
+        assert_eq!(vec![&b"b\n"[..], &b"a\n"[..]],
+                   newline.split_inclusive(b"a\nb\n").rev().collect::<Vec<_>>());
+
+        let mut parts = newline.split_inclusive(b"a\nb\nc");
+        assert_eq!(Some(&b"a\n"[..]), parts.next());
+        assert_eq!(Some(&b"c"[..]), parts.next_back());
+        assert_eq!(Some(&b"b\n"[..]), parts.next_back());
+        assert_eq!(None, parts.next());
+    }
+
+    #[test]
+    fn find_bytes_works_as_position_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+
+            searcher.find_bytes(&haystack) == haystack.iter().position(|&b| searcher.contains_byte(b))
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn byte_methods_accept_any_byte_container() {
+        use std::borrow::Cow;
+
+        let searcher = AsciiChars::from_bytes(b"=;");
+        let vec = b"key=value;".to_vec();
+        let cow: Cow<[u8]> = Cow::Borrowed(b"key=value;");
+
+        assert_eq!(Some(3), searcher.find_bytes(&vec));
+        assert_eq!(Some(3), searcher.find_bytes(vec.clone()));
+        assert_eq!(Some(3), searcher.find_bytes("key=value;"));
+        assert_eq!(Some(3), searcher.find_bytes(&cow));
+        assert_eq!(Some(3), searcher.find_bytes(*b"key=value;"));
+        assert_eq!(Some(9), searcher.find_nth("key=value;", 1));
+        assert_eq!(vec![(3, b'='), (9, b';')], searcher.match_indices(&vec).collect::<Vec<_>>());
+        assert_eq!(vec![&b"key"[..], &b"value"[..], &b""[..]], searcher.split("key=value;").collect::<Vec<_>>());
+        assert_eq!(b"key=value", searcher.trim(&cow[..]));
+
+        let with_fallback = searcher.with_generated_fallback();
+        assert_eq!(2, with_fallback.count(&vec));
+        assert!(with_fallback.contains(cow));
+    }
+
+    #[test]
+    fn fold_positions_visits_matches_as_match_indices_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let expected: Vec<_> = searcher.match_indices(&haystack).map(|(i, _)| i).collect();
+
+            let mut visited = Vec::new();
+            searcher.for_each_position(&haystack, |i| visited.push(i));
+            let folded = searcher.fold_positions(&haystack, Vec::new(), |mut acc, i| {
+                acc.push(i);
+                acc
+            });
+
+            visited == expected && folded == expected
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn fold_positions_returns_init_without_matches() {
+        let searcher = AsciiChars::from_bytes(b"<");
+        assert_eq!(7, searcher.fold_positions(b"0123456789abcdef", 7, |acc, i| acc + i));
+        assert_eq!(16 + 17, searcher.fold_positions(b"0123456789abcdef<<", 0, |acc, i| acc + i));
+    }
+
+    #[test]
+    fn set_or_substring_finds_whichever_comes_first() {
+        let xml = SetOrSubstring::new(AsciiChars::from_bytes(b"<>&"), Substring::new("]]>"));
+        assert_eq!(Some((3, MatchKind::Byte(b'&'))), xml.find(b"abc&]]>"));
+        assert_eq!(Some((3, MatchKind::Substring)), xml.find(b"abc]]>&"));
+        assert_eq!(Some((1, MatchKind::Substring)), xml.find(b"a]]>"));
+        assert_eq!(Some((2, MatchKind::Byte(b'<'))), xml.find(b"]]<]]>"));
+        assert_eq!(None, xml.find(b"]]]"));
+
+        // The substring wins when both start at the same index
+        let tie = SetOrSubstring::new(AsciiChars::from_bytes(b"]"), Substring::new("]]>"));
+        assert_eq!(Some((1, MatchKind::Substring)), tie.find(b"a]]>"));
+        assert_eq!(Some((1, MatchKind::Byte(b']'))), tie.find(b"a]]"));
+    }
+
+    #[test]
+    fn match_histogram_counts_each_character_in_order() {
+        let chars = AsciiChars::from_bytes(b",;\n,");
+        let histogram = chars.match_histogram(b"a,b,c;d\ne,f;");
+        assert_eq!([3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], histogram);
+        assert_eq!([0; 16], AsciiChars::new().match_histogram(b"abc"));
+
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let histogram = searcher.match_histogram(&haystack);
+
+            let each_agrees = v[..searcher.len()].iter().enumerate().all(|(i, c)| {
+                let first = v[..searcher.len()].iter().position(|d| d.0 == c.0) == Some(i);
+                let expected = haystack.iter().filter(|&&b| b == c.0 as u8).count();
+                histogram[i] == if first { expected } else { 0 }
+            });
+            each_agrees && histogram.iter().sum::<usize>()
+                == haystack.iter().filter(|b| searcher.contains_byte(**b)).count()
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn find_map_position_stops_at_the_first_value() {
+        // The first quote that isn't escaped
+        let haystack = br#"say \"hi\" and "bye""#;
+        let quotes = AsciiChars::from_bytes(b"\"");
+        let mut calls = 0;
+        let found = quotes.find_map_position(haystack, |i, b| {
+            calls += 1;
+            assert_eq!(b'"', b);
+            if haystack[i - 1] != b'\\' { Some(i) } else { None }
+        });
+        assert_eq!(Some(15), found);
+        assert_eq!(3, calls);
+
+        assert_eq!(None, quotes.find_map_position(b"no quotes", |i, _| Some(i)));
+        assert_eq!(Some(b'>'), AsciiChars::from_bytes(b"<>").find_map_position(b"a>b<", |_, b| Some(b)));
+    }
+
+    #[test]
+    fn find_bytes_aligned_works_as_find_bytes_does() {
+        #[repr(align(16))]
+        struct Aligned([u8; 64]);
+
+        fn prop(bytes: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+
+            let mut aligned = Aligned([0; 64]);
+            let len = cmp::min(bytes.len(), 64);
+            aligned.0[..len].copy_from_slice(&bytes[..len]);
+            let haystack = &aligned.0[..len];
+
+            let found = unsafe { searcher.find_bytes_aligned(haystack) };
+            found == searcher.find_bytes(haystack)
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn find_bytes_prefetched_works_as_find_bytes_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>, distance: usize) -> bool {
+            let searcher = searcher_from(&v);
+
+            searcher.find_bytes_prefetched(&haystack, distance % 4096) == searcher.find_bytes(&haystack)
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>, usize) -> bool);
+    }
+
+    #[test]
+    fn find_bytes_prefetched_crosses_chunks() {
+        let mut haystack = vec![b'a'; 2000];
+        let searcher = AsciiChars::from_bytes(b"<");
+        assert_eq!(None, searcher.find_bytes_prefetched(&haystack, 1024));
+
+        for &index in &[1, 255, 256, 257, 1999] {
+            haystack[index] = b'<';
+            assert_eq!(Some(index), searcher.find_bytes_prefetched(&haystack, 1024));
+            assert_eq!(Some(index - 1), searcher.find_bytes_prefetched(&haystack[1..], 1024));
+            haystack[index] = b'a';
+        }
+    }
+
+    #[test]
+    fn find_bytes_prefetched_takes_any_distance() {
+        let searcher = AsciiChars::from_bytes(b"<");
+        let haystack = vec![b'a'; 600];
+        assert_eq!(None, searcher.find_bytes_prefetched(&haystack, usize::MAX));
+        assert_eq!(Some(1), searcher.find_bytes_prefetched(b"a<", usize::MAX));
+    }
+
+    #[test]
+    fn scan_works_as_find_matching_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+
+            match searcher.scan(&haystack) {
+                ScanResult::Found { index, byte } => searcher.find_matching(&haystack) == Some((index, byte)),
+                ScanResult::NotFound { scanned } => {
+                    searcher.find_matching(&haystack).is_none() && scanned == haystack.len()
+                }
+            }
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn find_bytes_init_only_searches_the_initialized_prefix() {
+        use std::mem::MaybeUninit;
+
+        let mut buf = [MaybeUninit::<u8>::uninit(); 64];
+        for (slot, &b) in buf.iter_mut().zip(b"GET / HTTP/1.1\r\n") {
+            *slot = MaybeUninit::new(b);
+        }
+
+        let searcher = AsciiChars::from_bytes(b"\r\n");
+        unsafe {
+            assert_eq!(Some(14), searcher.find_bytes_init(&buf, 16));
+            assert_eq!(None, searcher.find_bytes_init(&buf, 14));
+            assert_eq!(None, searcher.find_bytes_init(&buf, 0));
+            assert_eq!(Some(2), searcher.find_bytes_init(&buf[12..], 4));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "65 bytes can't be initialized in a buffer of 64")]
+    fn find_bytes_init_rejects_more_than_the_buffer() {
+        use std::mem::MaybeUninit;
+
+        let buf = [MaybeUninit::<u8>::uninit(); 64];
+        let _ = unsafe { AsciiChars::from_bytes(b"\n").find_bytes_init(&buf, 65) };
+    }
+
+    #[test]
+    fn char_position_counts_chars_before_the_match() {
+        let searcher = AsciiChars::from_bytes(b"<>");
+        assert_eq!(Some(3), searcher.char_position("aéü<b>"));
+        assert_eq!(Some(0), searcher.char_position("<\u{1F600}"));
+        assert_eq!(None, searcher.char_position("\u{1F600}é"));
+
+        fn prop(s: String, c: AsciiChar) -> bool {
+            let expected = s.chars().position(|d| d == c.0);
+            AsciiChars::from_bytes(&[c.0 as u8]).char_position(&s) == expected
+        }
+        quickcheck(prop as fn(String, AsciiChar) -> bool);
+    }
+
+    #[test]
+    fn find_bytes_or_returns_the_sentinel_when_not_found() {
+        let searcher = AsciiChars::from_bytes(b"<>");
+        assert_eq!(3, searcher.find_bytes_or(b"abc<", usize::MAX));
+        assert_eq!(usize::MAX, searcher.find_bytes_or(b"abc", usize::MAX));
+        assert_eq!(0, searcher.find_bytes_or(b"", 0));
+    }
+
+    #[test]
+    fn split_at_first_returns_the_delimiter() {
+        let delimiters = AsciiChars::from_bytes(b":=#");
+        assert_eq!(Some((&b"key"[..], b'=', &b"value # note"[..])),
+                   delimiters.split_at_first(b"key=value # note"));
+        assert_eq!(Some((&b""[..], b'#', &b""[..])), delimiters.split_at_first(b"#"));
+        assert_eq!(None, delimiters.split_at_first(b"no delimiters"));
+        assert_eq!(None, delimiters.split_at_first(b""));
+    }
+
+    #[test]
+    fn scan_reports_what_was_scanned() {
+        let searcher = AsciiChars::from_bytes(b"\r\n");
+        assert_eq!(ScanResult::Found { index: 5, byte: b'\r' }, searcher.scan(b"hello\r\n"));
+        assert_eq!(ScanResult::NotFound { scanned: 5 }, searcher.scan(b"hello"));
+        assert_eq!(ScanResult::NotFound { scanned: 0 }, searcher.scan(b""));
+    }
+
+    #[test]
+    fn find_with_budget_stops_at_the_budget() {
+        let searcher = AsciiChars::from_bytes(b"\r\n");
+        assert_eq!(BudgetResult::Found(5), searcher.find_with_budget(b"hello\r\n", 6));
+        assert_eq!(BudgetResult::BudgetReached(5), searcher.find_with_budget(b"hello\r\n", 5));
+        assert_eq!(BudgetResult::Exhausted, searcher.find_with_budget(b"hello", 5));
+        assert_eq!(BudgetResult::Exhausted, searcher.find_with_budget(b"hello", 100));
+        assert_eq!(BudgetResult::Exhausted, searcher.find_with_budget(b"", 0));
+        assert_eq!(BudgetResult::BudgetReached(0), searcher.find_with_budget(b"\n", 0));
+
+        // Carrying on from where the budget ran out
+        let haystack = b"a long line without an end yet\n";
+        let mut offset = 0;
+        let found = loop {
+            match searcher.find_with_budget(&haystack[offset..], 8) {
+                BudgetResult::Found(index) => break Some(offset + index),
+                BudgetResult::Exhausted => break None,
+                BudgetResult::BudgetReached(scanned) => offset += scanned,
+            }
+        };
+        assert_eq!(Some(haystack.len() - 1), found);
+    }
+
+    #[test]
+    fn searches_agree_with_checking_each_byte() {
+        #[repr(align(16))]
+        struct Aligned([u8; 80]);
+
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>, start: u8) -> bool {
+            let needle = searcher_from(&v);
+
+            // Every length up to 64, at every position in a 16-byte
+            // window, including bytes that aren't ASCII
+            let len = cmp::min(haystack.len(), 64);
+            let start = start as usize % 16;
+            let mut aligned = Aligned([0; 80]);
+            aligned.0[start..start + len].copy_from_slice(&haystack[..len]);
+
+            for end in start..start + len + 1 {
+                super::check_parity(&needle, &aligned.0[start..end]);
+            }
+            true
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>, u8) -> bool);
+    }
+
+    #[test]
+    fn searches_agree_at_window_boundaries() {
+        let needle = AsciiChars::from_bytes(b"<>&");
+        let mut haystack = [0x80u8; 80];
+
+        for at in 0..haystack.len() {
+            haystack[at] = b'&';
+            for start in 0..cmp::min(at + 1, 17) {
+                super::check_parity(&needle, &haystack[start..]);
+                super::check_parity(&needle, &haystack[start..at + 1]);
+            }
+            haystack[at] = 0x80;
+        }
+    }
+
+    #[test]
+    fn find_matching_returns_the_character_found() {
+        let searcher = AsciiChars::from_bytes(b"=;");
+        assert_eq!(Some((3, b'=')), searcher.find_matching(b"key=value;"));
+        assert_eq!(Some((5, b';')), searcher.find_matching(b"value;key=value"));
+        assert_eq!(Some((20, b';')), searcher.find_matching(b"01234567890123456789;"));
+        assert_eq!(None, searcher.find_matching(b"value"));
+        assert_eq!(None, searcher.find_matching(b""));
+    }
+
+    #[test]
+    fn match_indices_works_as_match_indices_does() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let chars: Vec<_> = v[..searcher.len()].iter().map(|c| c.0).collect();
+
+            searcher.match_indices(s.as_bytes())
+                .eq(s.match_indices(&chars[..]).map(|(i, m)| (i, m.as_bytes()[0])))
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn match_indices_yields_members_of_the_set_in_order() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let matches: Vec<_> = searcher.match_indices(&haystack).collect();
+
+            matches.iter().all(|&(i, b)| searcher.contains_byte(b) && haystack[i] == b) &&
+                matches.windows(2).all(|w| w[0].0 < w[1].0)
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn match_indices_is_fused() {
+        let mut iter = AsciiChars::from_bytes(b"<").match_indices(b"0123456789abcdef<");
+        assert_eq!(Some((16, b'<')), iter.next());
+        assert_eq!(None, iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    fn for_each_position_mut_visits_original_positions() {
+        let searcher = AsciiChars::from_bytes(b",;");
+        let mut haystack = b"a,b;;0123456789abcdef,".to_vec();
+        let mut visited = Vec::new();
+
+        // Writing a character in the set must not make it match again
+        searcher.for_each_position_mut(&mut haystack, |idx, byte| {
+            visited.push((idx, *byte));
+            *byte = b';';
+        });
+
+        assert_eq!(vec![(1, b','), (3, b';'), (4, b';'), (21, b',')], visited);
+        assert_eq!(&b"a;b;;0123456789abcdef;"[..], &haystack[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn replace_all_cow_works_as_replace_all_does() {
+        use std::borrow::Cow;
+
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>, replacement: u8) -> bool {
+            let searcher = searcher_from(&v);
+
+            let replaced = searcher.replace_all_cow(&haystack, replacement);
+            let borrowed = match replaced {
+                Cow::Borrowed(_) => true,
+                Cow::Owned(_) => false,
+            };
+            *replaced == *searcher.replace_all(&haystack, replacement) &&
+                borrowed == searcher.find_bytes(&haystack).is_none()
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>, u8) -> bool);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn replace_all_cow_borrows_clean_input() {
+        use std::borrow::Cow;
+
+        let searcher = AsciiChars::from_bytes(b"<>");
+        assert!(match searcher.replace_all_cow(b"clean", b'_') {
+            Cow::Borrowed(bytes) => bytes == b"clean",
+            Cow::Owned(_) => false,
+        });
+        assert_eq!(&b"_a_"[..], &*searcher.replace_all_cow(b"<a>", b'_'));
+    }
+
+    #[test]
+    fn replace_all_in_place_only_touches_characters_in_the_set() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>, replacement: u8) -> bool {
+            let searcher = searcher_from(&v);
+            let expected: Vec<_> = haystack.iter()
+                .map(|&b| if searcher.contains_byte(b) { replacement } else { b })
+                .collect();
+
+            let mut in_place = haystack.clone();
+            let count = searcher.replace_all_in_place(&mut in_place, replacement);
+
+            in_place == expected &&
+                count == haystack.iter().filter(|&&b| searcher.contains_byte(b)).count()
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>, u8) -> bool);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn replace_all_copies_the_haystack() {
+        let control = AsciiChars::from_bytes(b"\t\r\n");
+        let line = b"name\tvalue\r\n";
+        assert_eq!(&b"name value  "[..], &control.replace_all(line, b' ')[..]);
+        assert_eq!(b"name\tvalue\r\n", line);
+    }
+
+    #[test]
+    fn replace_all_in_place_counts_replacements() {
+        let control = AsciiChars::from_bytes(b"\t\r\n");
+        let mut line = *b"name\tvalue\r\n";
+        assert_eq!(3, control.replace_all_in_place(&mut line, b' '));
+        assert_eq!(b"name value  ", &line);
+
+        // Replacing with a character in the set doesn't find it again
+        let mut tabs = *b"\t\t\t";
+        assert_eq!(3, control.replace_all_in_place(&mut tabs, b'\t'));
+    }
+
+    #[test]
+    fn all_in_set_works_as_all_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let n = searcher.len();
+
+            // Mostly members of the set, so that `true` comes up too
+            let haystack: Vec<u8> = haystack.iter()
+                .map(|&b| if n > 0 && b < 0xf0 { searcher.byte_at(b as usize % n) } else { b })
+                .collect();
+
+            searcher.all_in_set(&haystack) == haystack.iter().all(|&b| searcher.contains_byte(b))
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn all_in_set_finds_offenders_anywhere() {
+        let hex = AsciiChars::from_bytes(b"0123456789abcdef");
+        assert!(hex.all_in_set(b""));
+        assert!(hex.all_in_set("deadbeef0123456789abcdef"));
+        assert!(!hex.all_in_set("deadbeef0123456789abcdeg"));
+        assert!(!hex.all_in_set("Deadbeef"));
+        assert!(!AsciiChars::new().all_in_set("a"));
+    }
+
+    #[test]
+    fn trim_works_as_trim_matches_does() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let chars: Vec<_> = v[..searcher.len()].iter().map(|c| c.0).collect();
+
+            searcher.trim_start(s.as_bytes()) == s.trim_start_matches(&chars[..]).as_bytes() &&
+                searcher.trim_end(s.as_bytes()) == s.trim_end_matches(&chars[..]).as_bytes() &&
+                searcher.trim(s.as_bytes()) == s.trim_matches(&chars[..]).as_bytes()
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn trim_crosses_16_byte_boundaries() {
+        let space = AsciiChars::from_bytes(b" \t");
+        let padded = b"                 \t value\t                  ";
+        assert_eq!(b"value\t                  ", space.trim_start(padded));
+        assert_eq!(b"                 \t value", space.trim_end(padded));
+        assert_eq!(b"value", space.trim(padded));
+    }
+
+    #[test]
+    fn trim_of_only_characters_in_the_set_is_empty() {
+        let space = AsciiChars::from_bytes(b" ");
+        let spaces = [b' '; 40];
+        assert!(space.trim_start(&spaces).is_empty());
+        assert!(space.trim_end(&spaces).is_empty());
+        assert!(space.trim(&spaces).is_empty());
+        assert!(space.trim(b"").is_empty());
+    }
+
+    #[test]
+    fn find_nth_works_as_match_indices_nth_does() {
+        fn prop(s: String, v: Vec<AsciiChar>, n: u8) -> bool {
+            let n = n as usize % 8;
+            let searcher = searcher_from(&v);
+            let chars: Vec<_> = v[..searcher.len()].iter().map(|c| c.0).collect();
+
+            searcher.find_nth(s.as_bytes(), n) == s.match_indices(&chars[..]).nth(n).map(|(i, _)| i)
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>, u8) -> bool);
+    }
+
+    #[test]
+    fn find_within_only_looks_up_to_the_limit() {
+        let delim = AsciiChars::from_bytes(b";");
+        let haystack = b"0123456789abcdef;";
+        assert_eq!(None, delim.find_within(haystack, 16));
+        assert_eq!(Some(16), delim.find_within(haystack, 17));
+        assert_eq!(Some(16), delim.find_within(haystack, 200));
+        assert_eq!(None, delim.find_within(haystack, 0));
+
+        assert_eq!(Some(2), delim.find_nth(b";;;", 2));
+        assert_eq!(None, delim.find_nth(b";;;", 3));
+    }
+
+    #[test]
+    fn find_bytes_matches_find_for_utf8() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+
+            searcher.find_bytes(s.as_bytes()) == s.find(searcher)
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn memchr_works_as_position_does() {
+        fn prop(haystack: Vec<u8>, (n1, n2, n3): (u8, u8, u8)) -> bool {
+            let position = |f: &dyn Fn(u8) -> bool| haystack.iter().position(|&b| f(b));
+
+            super::memchr(n1, &haystack) == position(&|b| b == n1) &&
+            super::memchr2(n1, n2, &haystack) == position(&|b| b == n1 || b == n2) &&
+            super::memchr3(n1, n2, n3, &haystack) == position(&|b| b == n1 || b == n2 || b == n3) &&
+            super::memrchr(n1, &haystack) == haystack.iter().rposition(|&b| b == n1)
+        }
+        quickcheck(prop as fn(Vec<u8>, (u8, u8, u8)) -> bool);
+    }
+
+    #[test]
+    fn memchr_finds_non_ascii_bytes() {
+        let haystack = [0u8, 0x80, 0xff, 0x80, 0];
+        assert_eq!(Some(1), super::memchr(0x80, &haystack));
+        assert_eq!(Some(3), super::memrchr(0x80, &haystack));
+        assert_eq!(Some(2), super::memchr2(0xff, 0xfe, &haystack));
+        assert_eq!(None, super::memchr3(0x7f, 0x81, 0xfe, &haystack));
+        assert_eq!(None, super::memchr(0, &[]));
+        assert_eq!(None, super::memrchr(0, &[]));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    #[ignore = "allocates and repeatedly searches 5 GiB"]
+    fn find_works_past_4_gib() {
+        // Each search below reads the whole buffer, as does the UTF-8
+        // check, so this needs 5 GiB of memory and takes a while.
+        let index = (5 << 30) - 3;
+        let mut haystack = vec![0u8; 5 << 30];
+        haystack[index] = b'&';
+
+        let needle = AsciiChars::from_bytes(b"<&");
+        assert_eq!(Some(index), needle.find_bytes(&haystack));
+        assert_eq!(Some(index), needle.rfind_bytes(&haystack));
+        #[cfg(target_arch = "x86_64")]
+        {
+            if super::has_sse42() {
+                let handler = super::UnalignedByteSliceHandler { operation: needle };
+                assert_eq!(Some(index), handler.find(&haystack));
+                assert_eq!(Some(index), handler.find(&haystack[1..]).map(|i| i + 1));
+            }
+        }
+
+        let haystack = ::std::str::from_utf8(&haystack).unwrap();
+        assert_eq!(Some(index), Substring::new("&").find(haystack));
+    }
+
+    #[test]
+    fn union_finds_characters_from_either_set() {
+        fn prop(s: String, a: Vec<AsciiChar>, b: Vec<AsciiChar>) -> bool {
+            let a: AsciiChars = a.iter().take(8).map(|c| c.0 as u8).collect();
+            let b: AsciiChars = b.iter().take(8).map(|c| c.0 as u8).collect();
+            let both = a.union(b);
+
+            let expected = s.bytes().position(|byte| a.contains_byte(byte) || b.contains_byte(byte));
+            both.find(&s) == expected
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>, Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn union_skips_characters_already_present() {
+        let both = AsciiChars::from_bytes(b"<>").union(AsciiChars::from_bytes(b">&<"));
+        assert_eq!(AsciiChars::from_bytes(b"<>&"), both);
+
+        let full = AsciiChars::from_bytes(b"abcdefghijklmnop");
+        assert_eq!(Ok(full), full.try_union(AsciiChars::from_bytes(b"pona")));
+    }
+
+    #[test]
+    fn try_union_fails_with_more_than_16_distinct_characters() {
+        let a = AsciiChars::from_bytes(b"abcdefghijkl");
+        let b = AsciiChars::from_bytes(b"ijklmnopqr");
+        assert_eq!(Err(super::TooManyBytes { len: 18 }), a.try_union(b));
+    }
+
+    #[test]
+    fn from_conversions_work_as_from_bytes_does() {
+        assert_eq!(AsciiChars::from_bytes(b"<>&"), AsciiChars::from(b"<>&"));
+        assert_eq!(AsciiChars::from_bytes(b""), AsciiChars::from(b""));
+        assert_eq!(AsciiChars::from_bytes(b"<"), AsciiChars::from(b'<'));
+        assert_eq!(AsciiChars::from_bytes(b"<"), AsciiChars::from('<'));
+        assert_eq!(Some(4), AsciiChars::from(b"<>&").find("abc &"));
+    }
+
+    #[test]
+    fn try_from_slice_works_as_try_from_bytes_does() {
+        use std::convert::TryFrom;
+
+        assert_eq!(Ok(AsciiChars::from_bytes(b"<>&")), AsciiChars::try_from(&b"<>&"[..]));
+        assert_eq!(Ok(AsciiChars::new()), AsciiChars::try_from(&b""[..]));
+
+        let err = AsciiChars::try_from(&[b'a'; 20][..]).unwrap_err();
+        assert_eq!(super::TooManyBytes { len: 20 }, err);
+        assert_eq!("AsciiChars can search for at most 16 bytes, but 20 were given", err.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "at most 16")]
+    fn from_array_panics_with_more_than_16_bytes() {
+        let _ = AsciiChars::from(&[b'a'; 17]);
+    }
+
+    #[test]
+    #[should_panic(expected = "not 'é'")]
+    fn from_char_panics_when_not_ascii() {
+        let _ = AsciiChars::from('é');
+    }
+
+    #[test]
+    fn positions_into_works_as_match_indices_does() {
+        fn prop(s: String, v: Vec<AsciiChar>, capacity: u8) -> bool {
+            let capacity = capacity as usize % 8;
+            let searcher = searcher_from(&v);
+            let chars: Vec<_> = v[..searcher.len()].iter().map(|c| c.0).collect();
+
+            let mut out = [0; 8];
+            let written = searcher.positions_into(s.as_bytes(), &mut out[..capacity]);
+            let expected: Vec<_> = s.match_indices(&chars[..]).map(|(i, _)| i).take(capacity).collect();
+            &out[..written] == &expected[..]
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>, u8) -> bool);
+    }
+
+    #[test]
+    fn positions_into_stops_when_full() {
+        let needle = AsciiChars::from_bytes(b"<>");
+        let mut out = [0; 2];
+        assert_eq!(2, needle.positions_into(b"<a><b>", &mut out));
+        assert_eq!([0, 2], out);
+
+        let mut out = [0; 4];
+        assert_eq!(1, needle.positions_into(b"ab>", &mut out));
+        assert_eq!(0, needle.positions_into(b"<>", &mut []));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_count_works_as_count_does() {
+        fn prop(s: String, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+
+            searcher.par_count(s.as_bytes()) == searcher.with_generated_fallback().count(&s)
+        }
+        quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_count_counts_across_chunks() {
+        let mut haystack = vec![b'a'; 1024 * 1024 + 7];
+        for i in (0..haystack.len()).step_by(1021) {
+            haystack[i] = b'&';
+        }
+        let needle = AsciiChars::from_bytes(b"<&");
+        let expected = haystack.iter().filter(|&&b| b == b'&').count();
+        assert_eq!(expected, needle.par_count(&haystack));
+    }
+
+    #[test]
+    fn count_byte_works_as_filter_does() {
+        fn prop(haystack: Vec<u8>, needle: u8) -> bool {
+            super::count_byte(needle, &haystack) == haystack.iter().filter(|&&b| b == needle).count()
+        }
+        quickcheck(prop as fn(Vec<u8>, u8) -> bool);
+    }
+
+    #[test]
+    fn count_byte_counts_the_tail() {
+        for len in 0..50 {
+            let mut haystack = vec![b'\n'; len];
+            assert_eq!(len, super::count_byte(b'\n', &haystack));
+
+            if let Some(last) = haystack.last_mut() {
+                *last = b'a';
+            }
+            assert_eq!(len.saturating_sub(1), super::count_byte(b'\n', &haystack));
+            assert_eq!(cmp::min(len, 1), super::count_byte(b'a', &haystack));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn line_index_finds_lines_and_columns() {
+        let index = LineIndex::new(b"ab\ncde\n\nf");
+        assert_eq!((0, 0), index.line_col(0));
+        assert_eq!((0, 2), index.line_col(2));
+        assert_eq!((1, 0), index.line_col(3));
+        assert_eq!((1, 3), index.line_col(6));
+        assert_eq!((2, 0), index.line_col(7));
+        assert_eq!((3, 0), index.line_col(8));
+        assert_eq!((3, 1), index.line_col(9));
+        assert_eq!((3, 1), index.line_col(100));
+
+        assert_eq!((0, 0), LineIndex::new(b"").line_col(5));
+        assert_eq!((1, 0), LineIndex::new(b"\n").line_col(1));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn line_index_works_as_counting_does() {
+        fn prop(haystack: Vec<u8>, offset: usize) -> bool {
+            let offset = offset % (haystack.len() + 1);
+            let before = &haystack[..offset];
+            let line = before.iter().filter(|&&b| b == b'\n').count();
+            let col = before.iter().rev().take_while(|&&b| b != b'\n').count();
+
+            LineIndex::new(&haystack).line_col(offset) == (line, col)
+        }
+        quickcheck(prop as fn(Vec<u8>, usize) -> bool);
+    }
+
+    #[cfg(feature = "serde")]
+    fn deserialize_bytes(bytes: &[u8]) -> AsciiChars {
+        use serde::Deserialize;
+        use serde::de::value::{BytesDeserializer, Error};
+
+        AsciiChars::deserialize(BytesDeserializer::<Error>::new(bytes)).unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_uses_the_bytes_in_the_set() {
+        use self::serde_test::{assert_ser_tokens, Token};
+
+        let mut chars = AsciiChars::from_bytes(b"<>");
+        chars.push(b'&');
+        assert_ser_tokens(&chars, &[Token::Bytes(b"<>&")]);
+        assert_ser_tokens(&AsciiChars::new(), &[Token::Bytes(b"")]);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_round_trips_any_set() {
+        fn prop(v: Vec<AsciiChar>) -> bool {
+            let n = cmp::min(super::MAXBYTES as usize, v.len());
+            let bytes: Vec<_> = v[..n].iter().map(|c| c.0 as u8).collect();
+            let chars = AsciiChars::from_bytes(&bytes);
+
+            format!("{:?}", chars) == format!("{:?}", deserialize_bytes(&bytes))
+        }
+        quickcheck(prop as fn(Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn serde_rejects_invalid_sets() {
+        use self::serde_test::{assert_de_tokens_error, Token};
+
+        assert_de_tokens_error::<AsciiChars>(
+            &[Token::Bytes(b"0123456789ABCDEFG")],
+            "invalid length 17, expected at most 16 ASCII bytes");
+        assert_de_tokens_error::<AsciiChars>(
+            &[Token::Bytes(b"<\xff")],
+            "invalid value: integer `255`, expected at most 16 ASCII bytes");
+        assert_de_tokens_error::<AsciiChars>(
+            &[Token::Seq { len: None },
+              Token::U8(b'0'), Token::U8(b'1'), Token::U8(b'2'), Token::U8(b'3'),
+              Token::U8(b'4'), Token::U8(b'5'), Token::U8(b'6'), Token::U8(b'7'),
+              Token::U8(b'8'), Token::U8(b'9'), Token::U8(b'A'), Token::U8(b'B'),
+              Token::U8(b'C'), Token::U8(b'D'), Token::U8(b'E'), Token::U8(b'F'),
+              Token::U8(b'G')],
+            "invalid length 17, expected at most 16 ASCII bytes");
+    }
+
+    #[test]
+    fn can_search_for_nul_bytes() {
+        assert_eq!(Some(1), "a\0".find(ascii_chars!('\0')));
+        assert_eq!(Some(0), "\0".find(ascii_chars!('\0')));
+        assert_eq!(None, "".find(ascii_chars!('\0')));
+    }
+
+    #[test]
+    fn can_search_in_nul_bytes() {
+        assert_eq!(Some(1), "\0a".find(ascii_chars!('a')));
+        assert_eq!(None, "\0".find(ascii_chars!('a')));
+    }
+
+    #[test]
+    fn pattern_does_not_backtrack_after_first() {
+        let mut searcher = ascii_chars!(' ').into_searcher("hello w ");
+        assert_eq!(SearchStep::Reject(0,5), searcher.next());
+        assert_eq!(SearchStep::Match(5,6), searcher.next());
+        assert_eq!(SearchStep::Reject(6,7), searcher.next());
+        assert_eq!(SearchStep::Match(7,8), searcher.next());
+        assert_eq!(SearchStep::Done, searcher.next());
+    }
+
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn space_is_found() {
+        // Since the algorithm operates on 16-byte chunks, it's
+        // important to cover tests around that boundary. Since 16
+        // isn't that big of a number, we might as well do all of
+        // them.
+
+        assert_eq!(Some(0),  SPACE.find(" "));
+        assert_eq!(Some(1),  SPACE.find("0 "));
+        assert_eq!(Some(2),  SPACE.find("01 "));
+        assert_eq!(Some(3),  SPACE.find("012 "));
+        assert_eq!(Some(4),  SPACE.find("0123 "));
+        assert_eq!(Some(5),  SPACE.find("01234 "));
+        assert_eq!(Some(6),  SPACE.find("012345 "));
+        assert_eq!(Some(7),  SPACE.find("0123456 "));
+        assert_eq!(Some(8),  SPACE.find("01234567 "));
+        assert_eq!(Some(9),  SPACE.find("012345678 "));
+        assert_eq!(Some(10), SPACE.find("0123456789 "));
+        assert_eq!(Some(11), SPACE.find("0123456789A "));
+        assert_eq!(Some(12), SPACE.find("0123456789AB "));
+        assert_eq!(Some(13), SPACE.find("0123456789ABC "));
+        assert_eq!(Some(14), SPACE.find("0123456789ABCD "));
+        assert_eq!(Some(15), SPACE.find("0123456789ABCDE "));
+        assert_eq!(Some(16), SPACE.find("0123456789ABCDEF "));
+        assert_eq!(Some(17), SPACE.find("0123456789ABCDEFG "));
+    }
+
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn space_not_found() {
+        // Since the algorithm operates on 16-byte chunks, it's
+        // important to cover tests around that boundary. Since 16
+        // isn't that big of a number, we might as well do all of
+        // them.
+
+        assert_eq!(None, SPACE.find(""));
+        assert_eq!(None, SPACE.find("0"));
+        assert_eq!(None, SPACE.find("01"));
+        assert_eq!(None, SPACE.find("012"));
+        assert_eq!(None, SPACE.find("0123"));
+        assert_eq!(None, SPACE.find("01234"));
+        assert_eq!(None, SPACE.find("012345"));
+        assert_eq!(None, SPACE.find("0123456"));
+        assert_eq!(None, SPACE.find("01234567"));
+        assert_eq!(None, SPACE.find("012345678"));
+        assert_eq!(None, SPACE.find("0123456789"));
+        assert_eq!(None, SPACE.find("0123456789A"));
+        assert_eq!(None, SPACE.find("0123456789AB"));
+        assert_eq!(None, SPACE.find("0123456789ABC"));
+        assert_eq!(None, SPACE.find("0123456789ABCD"));
+        assert_eq!(None, SPACE.find("0123456789ABCDE"));
+        assert_eq!(None, SPACE.find("0123456789ABCDEF"));
+        assert_eq!(None, SPACE.find("0123456789ABCDEFG"));
+    }
+
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn works_on_nonaligned_beginnings() {
+        // We have special code for strings that don't lie on 16-byte
+        // boundaries. Since allocation seems to happen on these
+        // boundaries by default, let's walk around a bit.
+
+        let s = "0123456789ABCDEF ".to_string();
+
+        assert_eq!(Some(16), SPACE.find(&s[ 0..]));
+        assert_eq!(Some(15), SPACE.find(&s[ 1..]));
+        assert_eq!(Some(14), SPACE.find(&s[ 2..]));
+        assert_eq!(Some(13), SPACE.find(&s[ 3..]));
+        assert_eq!(Some(12), SPACE.find(&s[ 4..]));
+        assert_eq!(Some(11), SPACE.find(&s[ 5..]));
+        assert_eq!(Some(10), SPACE.find(&s[ 6..]));
+        assert_eq!(Some(9),  SPACE.find(&s[ 7..]));
+        assert_eq!(Some(8),  SPACE.find(&s[ 8..]));
+        assert_eq!(Some(7),  SPACE.find(&s[ 9..]));
+        assert_eq!(Some(6),  SPACE.find(&s[10..]));
+        assert_eq!(Some(5),  SPACE.find(&s[11..]));
+        assert_eq!(Some(4),  SPACE.find(&s[12..]));
+        assert_eq!(Some(3),  SPACE.find(&s[13..]));
+        assert_eq!(Some(2),  SPACE.find(&s[14..]));
+        assert_eq!(Some(1),  SPACE.find(&s[15..]));
+        assert_eq!(Some(0),  SPACE.find(&s[16..]));
+        assert_eq!(None,     SPACE.find(&s[17..]));
+    }
 
-        let mut bytes = needle.as_bytes().chunks(8);
-        let needle_lo = bytes.next().map(pack_needle_bytes).unwrap_or(0);
-        let needle_hi = bytes.next().map(pack_needle_bytes).unwrap_or(0);
+    #[test]
+    fn works_as_rfind_does_for_single_characters() {
+        fn prop(s: String, c: AsciiChar) -> bool {
+            ascii_chars!(c.0).rfind(&s) == s.rfind(c.0)
+        }
+        quickcheck(prop as fn(String, AsciiChar) -> bool);
+    }
 
-        Substring {
-            raw: needle,
-            needle_lo: needle_lo,
-            needle_hi: needle_hi,
-            needle_len: min(needle.len(), 16) as u8,
+    #[test]
+    fn works_as_rfind_does_for_multiple_characters() {
+        fn prop(s: String, (c1, c2, c3, c4): (AsciiChar, AsciiChar, AsciiChar, AsciiChar)) -> bool {
+            ascii_chars!(c1.0, c2.0, c3.0, c4.0).rfind(&s) == s.rfind(&[c1.0, c2.0, c3.0, c4.0][..])
         }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar, AsciiChar, AsciiChar)) -> bool);
     }
-}
 
-impl<'a> PackedCompareOperation for Substring<'a> {
-    unsafe fn initial(&self, haystack: *const u8, offset: usize, len: usize) -> u64 {
-        let matching_bytes;
-
-        asm!("movlhps $2, $1
-              pcmpestrm $$0b00001100, ($3), $1"
-             : // output operands
-             "={xmm0}"(matching_bytes)
-             : // input operands
-             "x"(self.needle_lo),
-             "x"(self.needle_hi),
-             "r"(haystack),
-             "{rax}"(self.needle_len as u64),
-             "{rdx}"(offset + len)
-             : // clobbers
-             "cc"
-             : // options
-        );
-
-        matching_bytes
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn space_is_found_in_reverse() {
+        assert_eq!(None,     SPACE.rfind(""));
+        assert_eq!(Some(0),  SPACE.rfind(" "));
+        assert_eq!(Some(0),  SPACE.rfind(" 0"));
+        assert_eq!(Some(0),  SPACE.rfind(" 012345678"));
+        assert_eq!(Some(0),  SPACE.rfind(" 0123456789ABCDE"));
+        assert_eq!(Some(0),  SPACE.rfind(" 0123456789ABCDEF"));
+        assert_eq!(Some(0),  SPACE.rfind(" 0123456789ABCDEFG"));
+        assert_eq!(Some(16), SPACE.rfind(" 0123456789ABCDE 0"));
+        assert_eq!(None,     SPACE.rfind("0123456789ABCDEFG"));
     }
 
-    unsafe fn body(&self, haystack: *const u8, offset: usize, len: usize) -> u32 {
-        let matching_idx;
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn works_on_nonaligned_endings_in_reverse() {
+        // The reverse search starts with the block containing the
+        // end of the string, so walk the end around instead.
 
-        asm!("movlhps $2, $1
-              pcmpestri $$0b00001100, ($3, $4), $1"
-             : // output operands
-             "={ecx}"(matching_idx)
-             : // input operands
-             "x"(self.needle_lo),
-             "x"(self.needle_hi),
-             "r"(haystack),
-             "r"(offset),
-             "{rax}"(self.needle_len as u64),
-             "{rdx}"(len)
-             : // clobbers
-             "cc"
-             : // options
-        );
+        let s = " 0123456789ABCDEF".to_string();
 
-        matching_idx
+        for end in 1..s.len() + 1 {
+            assert_eq!(Some(0), SPACE.rfind(&s[..end]));
+        }
+        for start in 1..s.len() + 1 {
+            assert_eq!(None, SPACE.rfind(&s[start..]));
+        }
     }
-}
 
-unsafe impl<'a> DirectSearch for Substring<'a> {
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn find(&self, haystack: &str) -> Option<usize> {
-        // It's ok to treat the haystack as a bag of bytes because the
-        // needle is guaranteed to only match complete UTF-8
-        // characters. Whenever a match is found, we double-check the
-        // match position with the complete needle.
+    #[test]
+    fn positions_works_as_match_indices_does() {
+        fn prop(s: String, (c1, c2, c3): (AsciiChar, AsciiChar, AsciiChar)) -> bool {
+            let us: Vec<_> = ascii_chars!(c1.0, c2.0, c3.0).positions(&s).collect();
+            let them: Vec<_> = s.match_indices(&[c1.0, c2.0, c3.0][..]).map(|(i, _)| i).collect();
+            us == them
+        }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar, AsciiChar)) -> bool);
+    }
 
-        let needle = self.raw.as_bytes();
-        let haystack = haystack.as_bytes();
+    #[test]
+    fn positions_is_fused() {
+        let mut positions = ascii_chars!('a').positions("aba");
+        assert_eq!(Some(0), positions.next());
+        assert_eq!(Some(2), positions.next());
+        assert_eq!(None, positions.next());
+        assert_eq!(None, positions.next());
+    }
 
-        if needle.len() == 0 && haystack.len() == 0 {
-            return Some(0);
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn positions_crosses_16_byte_boundaries() {
+        let positions: Vec<_> = SPACE.positions(" 123456789ABCDE  123456789ABCDE ").collect();
+        assert_eq!(&positions, &[0, 15, 16, 31]);
+    }
+
+    #[test]
+    fn positions_works_as_rmatch_indices_does_in_reverse() {
+        fn prop(s: String, (c1, c2): (AsciiChar, AsciiChar)) -> bool {
+            let chars = [c1.0, c2.0];
+            let us = ascii_chars!(c1.0, c2.0).positions(&s).rev();
+            let them = s.rmatch_indices(&chars[..]).map(|(i, _)| i);
+            us.eq(them)
         }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar)) -> bool);
+    }
 
-        let searcher = UnalignedByteSliceHandler { operation: *self };
-        let mut offset = 0;
+    #[test]
+    fn positions_from_both_ends_meet_in_the_middle() {
+        let brackets = ascii_chars!('(', ')');
+        let mut positions = brackets.positions("((a)(b)) c (d) 0123456789ABCDEF)");
+
+        assert_eq!(Some(0), positions.next());
+        assert_eq!(Some(31), positions.next_back());
+        assert_eq!(Some(1), positions.next());
+        assert_eq!(Some(13), positions.next_back());
+        assert_eq!(Some(11), positions.next_back());
+        assert_eq!(Some(3), positions.next());
+        assert_eq!(Some(7), positions.next_back());
+        assert_eq!(Some(6), positions.next_back());
+        assert_eq!(Some(4), positions.next());
+        assert_eq!(None, positions.next());
+        assert_eq!(None, positions.next_back());
+    }
 
-        while let Some(pos) = searcher.find(&haystack[offset..]) {
-            // Found a match, but is it really?
-            if haystack[pos + offset..].starts_with(needle) {
-                return Some(offset + pos);
+    #[test]
+    fn positions_interleaved_from_both_ends_yield_every_match_once() {
+        fn prop(s: String, c: AsciiChar, directions: Vec<bool>) -> bool {
+            let mut positions = ascii_chars!(c.0).positions(&s);
+            let mut front = Vec::new();
+            let mut back = Vec::new();
+
+            let then_forwards = [true];
+            for &forwards in directions.iter().chain(then_forwards.iter().cycle()) {
+                let next = if forwards { positions.next() } else { positions.next_back() };
+                match next {
+                    Some(idx) if forwards => front.push(idx),
+                    Some(idx) => back.push(idx),
+                    None => break,
+                }
             }
 
-            // Skip past this false positive
-            offset += pos + 1;
+            front.extend(back.into_iter().rev());
+            front == s.match_indices(c.0).map(|(i, _)| i).collect::<Vec<_>>()
         }
-        None
+        quickcheck(prop as fn(String, AsciiChar, Vec<bool>) -> bool);
     }
 
-    #[cfg(not(all(feature = "unstable", target_arch = "x86_64")))]
-    fn find(&self, haystack: &str) -> Option<usize> {
-        haystack.find(self.raw)
+    #[test]
+    fn peek_returns_the_next_match_without_consuming_it() {
+        let brackets = ascii_chars!('(', ')');
+        let mut positions = brackets.positions("(a) 0123456789ABCDEF(");
+
+        assert_eq!(Some(0), positions.peek());
+        assert_eq!(Some(0), positions.peek());
+        assert_eq!(Some(0), positions.next());
+        assert_eq!(Some(2), positions.next());
+        assert_eq!(Some(20), positions.peek());
+        assert_eq!(Some(20), positions.next());
+        assert_eq!(None, positions.peek());
+        assert_eq!(None, positions.next());
     }
 
-    fn len(&self) -> usize {
-        self.raw.len()
+    #[test]
+    fn peeked_match_is_still_found_from_the_back() {
+        let brackets = ascii_chars!('(', ')');
+        let mut positions = brackets.positions("(a)");
+
+        assert_eq!(Some(0), positions.peek());
+        assert_eq!(Some(2), positions.next_back());
+        assert_eq!(Some(0), positions.next_back());
+        assert_eq!(None, positions.peek());
+        assert_eq!(None, positions.next());
     }
-}
 
-impl<'a> Pattern<'a> for Substring<'a> {
-    type Searcher = DirectSearcher<'a, Substring<'a>>;
+    #[test]
+    fn peeking_does_not_change_the_matches() {
+        fn prop(s: String, c: AsciiChar, peeks: Vec<bool>) -> bool {
+            let mut positions = ascii_chars!(c.0).positions(&s);
+            let mut found = Vec::new();
+
+            for &peek in peeks.iter().chain([false].iter().cycle()) {
+                let peeked = if peek { positions.peek() } else { None };
+                match positions.next() {
+                    Some(idx) if peek && peeked != Some(idx) => return false,
+                    Some(idx) => found.push(idx),
+                    None => break,
+                }
+            }
 
-    fn into_searcher(self, haystack: &'a str) -> DirectSearcher<'a, Substring<'a>> {
-        DirectSearcher {
-            haystack: haystack,
-            offset: 0,
-            direct_search: self,
+            found == s.match_indices(c.0).map(|(i, _)| i).collect::<Vec<_>>()
         }
+        quickcheck(prop as fn(String, AsciiChar, Vec<bool>) -> bool);
     }
-}
-
-/// Types that return the index of the next match.
-// Do we really want to expose the trait like this?
-pub unsafe trait DirectSearch {
-    fn find(&self, haystack: &str) -> Option<usize>;
-    fn len(&self) -> usize;
-}
-
-/// A searcher implementation for DirectSearch types.
-#[derive(Debug,Copy,Clone)]
-pub struct DirectSearcher<'a, D> {
-    haystack: &'a str,
-    offset: usize,
-    direct_search: D,
-}
 
-unsafe impl<'a, D> Searcher<'a> for DirectSearcher<'a, D>
-    where D: DirectSearch
-{
-    fn haystack(&self) -> &'a str {
-        self.haystack
+    #[test]
+    fn count_works_as_matches_does() {
+        fn prop(s: String, (c1, c2, c3): (AsciiChar, AsciiChar, AsciiChar)) -> bool {
+            ascii_chars!(c1.0, c2.0, c3.0).count(&s) == s.matches(&[c1.0, c2.0, c3.0][..]).count()
+        }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar, AsciiChar)) -> bool);
     }
 
-    #[inline]
-    fn next(&mut self) -> SearchStep {
-        if self.offset >= self.haystack.len() {
-            return SearchStep::Done;
+    #[test]
+    fn count_of_one_character_works_as_matches_does() {
+        fn prop(s: String, c: AsciiChar) -> bool {
+            ascii_chars!(c.0).count(&s) == s.matches(c.0).count()
         }
+        quickcheck(prop as fn(String, AsciiChar) -> bool);
+    }
 
-        let left_to_search = &self.haystack[self.offset..]; // TODO: unchecked_slice?
-        let idx = self.direct_search.find(left_to_search);
-
-        // If there's no match, then the rest of the string should be
-        // returned.
-        let idx = idx.unwrap_or(self.haystack.len());
-
-        let (res, next_offset) = if idx == 0 {
-            // A match occurs at the beginning of the string
-            let next = self.offset + self.direct_search.len();
-            (SearchStep::Match(self.offset, next), next)
-        } else {
-            // A match occurs somewhere further in the string
-            let next = self.offset + idx;
-            (SearchStep::Reject(self.offset, next), next)
-        };
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn count_handles_partial_blocks() {
+        let s = "  23456789ABCDE  123456789ABCDE  ".to_string();
 
-        self.offset = next_offset;
-        res
+        for start in 0..s.len() + 1 {
+            for end in start..s.len() + 1 {
+                let expected = s[start..end].matches(' ').count();
+                assert_eq!(expected, SPACE.count(&s[start..end]));
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
-    extern crate quickcheck;
-    extern crate libc;
-    extern crate rand;
+    #[test]
+    fn contains_works_as_contains_does() {
+        fn prop(s: String, (c1, c2): (AsciiChar, AsciiChar)) -> bool {
+            ascii_chars!(c1.0, c2.0).contains(&s) == s.contains(&[c1.0, c2.0][..])
+        }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar)) -> bool);
+    }
 
-    use super::{AsciiChars, Substring, DirectSearch};
-    use self::quickcheck::{quickcheck, Arbitrary, Gen};
-    use std::str::pattern::{Pattern, Searcher, SearchStep};
-    use std::cmp;
+    #[test]
     #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    use std::{slice, str, ptr};
+    fn contains_handles_partial_blocks() {
+        let s = "0123456789ABCDEF 0123456789ABCDEF".to_string();
+
+        assert!(!SPACE.contains(""));
+        assert!(!SPACE.contains(&s[..16]));
+        assert!(!SPACE.contains(&s[17..]));
+        for start in 0..17 {
+            assert!(SPACE.contains(&s[start..17]));
+        }
+    }
 
-    pub const SPACE: AsciiChars = AsciiChars::from_words(0x0000000000000020, 0, 1);
-    // < > &
-    pub const XML_DELIM_3: AsciiChars = AsciiChars::from_words(0x00000000003c3e26, 0, 3);
-    // < > & ' "
-    pub const XML_DELIM_5: AsciiChars = AsciiChars::from_words(0x0000003c3e262722, 0, 5);
+    #[test]
+    fn find_from_works_as_find_does_on_the_rest_of_the_string() {
+        fn prop(s: String, (c1, c2): (AsciiChar, AsciiChar), start: usize) -> bool {
+            let start = if s.is_empty() { 0 } else { start % (s.len() + 1) };
+            let expected = s.as_bytes()[start..].iter()
+                .position(|&b| b == c1.0 as u8 || b == c2.0 as u8)
+                .map(|idx| start + idx);
+            ascii_chars!(c1.0, c2.0).find_from(&s, start) == expected
+        }
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar), usize) -> bool);
+    }
 
-    #[derive(Debug,Copy,Clone)]
-    struct AsciiChar(char);
+    #[test]
+    fn find_from_is_none_at_or_past_the_end() {
+        let searcher = ascii_chars!('<');
+        assert_eq!(Some(16), searcher.find_from("<123456789ABCDEF<", 1));
+        assert_eq!(Some(16), searcher.find_from("<123456789ABCDEF<", 16));
+        assert_eq!(None, searcher.find_from("<123456789ABCDEF<", 17));
+        assert_eq!(None, searcher.find_from("<123456789ABCDEF<", 100));
+    }
 
-    impl Arbitrary for AsciiChar {
-        fn arbitrary<G>(g: &mut G) -> AsciiChar
-            where G: Gen
-        {
-            AsciiChar(g.gen_range::<u8>(0, 128) as char)
-        }
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn find_from_without_a_fallback() {
+        assert_eq!(Some(16), SPACE.find_from(" 123456789ABCDEF ", 1));
+        assert_eq!(None, SPACE.find_from(" 123456789ABCDEF ", 17));
+        assert_eq!(None, SPACE.find_from(" 123456789ABCDEF ", 18));
     }
 
     #[test]
-    fn works_as_find_does_for_single_characters() {
-        fn prop(s: String, c: AsciiChar) -> bool {
-            s.find(ascii_chars!(c.0)) == s.find(c.0)
+    fn find_not_works_as_find_does_with_a_negated_set() {
+        fn prop(s: String, (c1, c2, c3): (AsciiChar, AsciiChar, AsciiChar)) -> bool {
+            let us = ascii_chars!(c1.0, c2.0, c3.0).find_not(&s);
+            let find = s.find(|c| c != c1.0 && c != c2.0 && c != c3.0);
+            us == find
         }
-        quickcheck(prop as fn(String, AsciiChar) -> bool);
+        quickcheck(prop as fn(String, (AsciiChar, AsciiChar, AsciiChar)) -> bool);
     }
 
     #[test]
-    fn works_as_find_does_for_multiple_characters() {
-        fn prop(s: String, (c1, c2, c3, c4): (AsciiChar, AsciiChar, AsciiChar, AsciiChar)) -> bool {
-            s.find(ascii_chars!(c1.0, c2.0, c3.0, c4.0)) == s.find(&[c1.0, c2.0, c3.0, c4.0][..])
+    fn find_not_is_none_when_every_byte_is_in_the_set() {
+        let hex = ascii_chars!('0', '1', '2', '3', '4', '5', '6', '7',
+                               '8', '9', 'a', 'b', 'c', 'd', 'e', 'f');
+        assert_eq!(None, hex.find_not(""));
+        assert_eq!(None, hex.find_not("0123456789abcdef0123456789abcdef"));
+        assert_eq!(Some(32), hex.find_not("0123456789abcdef0123456789abcdefg"));
+    }
+
+    #[test]
+    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
+    fn find_not_handles_partial_blocks() {
+        let s = "                x               ".to_string();
+
+        for start in 0..s.len() + 1 {
+            for end in start..s.len() + 1 {
+                let expected = s[start..end].find(|c| c != ' ');
+                assert_eq!(expected, SPACE.find_not(&s[start..end]));
+            }
         }
-        quickcheck(prop as fn(String, (AsciiChar, AsciiChar, AsciiChar, AsciiChar)) -> bool);
     }
 
     #[test]
-    fn works_as_find_does_for_up_to_16_characters() {
+    #[cfg(target_arch = "x86_64")]
+    fn avx2_works_as_find_does_for_up_to_16_characters() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
         fn prop(s: String, v: Vec<AsciiChar>) -> bool {
             let n = cmp::min(super::MAXBYTES as usize, v.len());
             let mut searcher = AsciiChars::new();
@@ -659,123 +6649,156 @@ mod test {
                 chars[index] = c.0;
             }
 
-            let us = s.find(searcher.with_fallback(|b| {
-                chars[..n].iter().position(|&c| c == b as char).is_some()
-            }));
-
-            let find = s.find(&chars[..n]);
-
-            us == find
+            let us = unsafe { searcher.find_avx2(s.as_bytes()) };
+            us == s.find(&chars[..n])
         }
         quickcheck(prop as fn(String, Vec<AsciiChar>) -> bool);
     }
 
     #[test]
-    fn can_search_for_nul_bytes() {
-        assert_eq!(Some(1), "a\0".find(ascii_chars!('\0')));
-        assert_eq!(Some(0), "\0".find(ascii_chars!('\0')));
-        assert_eq!(None, "".find(ascii_chars!('\0')));
+    #[cfg(target_arch = "x86_64")]
+    fn find_bytes_with_lanes_works_as_find_bytes_does() {
+        fn prop(haystack: Vec<u8>, v: Vec<AsciiChar>) -> bool {
+            let searcher = searcher_from(&v);
+            let expected = searcher.find_bytes(&haystack);
+
+            let sse = !is_x86_feature_detected!("sse4.2") ||
+                unsafe { searcher.find_bytes_with_lanes::<16>(&haystack) } == expected;
+            let avx2 = !is_x86_feature_detected!("avx2") ||
+                unsafe { searcher.find_bytes_with_lanes::<32>(&haystack) } == expected;
+            sse && avx2
+        }
+        quickcheck(prop as fn(Vec<u8>, Vec<AsciiChar>) -> bool);
     }
 
     #[test]
-    fn can_search_in_nul_bytes() {
-        assert_eq!(Some(1), "\0a".find(ascii_chars!('a')));
-        assert_eq!(None, "\0".find(ascii_chars!('a')));
+    #[cfg(target_arch = "x86_64")]
+    fn single_byte_sse2_search_works_as_the_general_search_does() {
+        fn prop(haystack: Vec<u8>, c: AsciiChar) -> bool {
+            let chars = AsciiChars::from_bytes(&[c.0 as u8]);
+            let expected = haystack.iter().position(|&b| b == c.0 as u8);
+
+            let general = !is_x86_feature_detected!("sse4.2") ||
+                super::UnalignedByteSliceHandler { operation: chars }.find(&haystack) == expected;
+            general && chars.find_sse(&haystack) == expected
+        }
+        quickcheck(prop as fn(Vec<u8>, AsciiChar) -> bool);
+
+        for len in 0..40 {
+            for at in 0..len {
+                let mut haystack = vec![b'a'; len];
+                haystack[at] = b'<';
+                haystack.push(b'<');
+                assert_eq!(Some(at), super::find_byte_sse2(b'<', &haystack));
+            }
+            assert_eq!(None, super::find_byte_sse2(b'<', &vec![b'a'; len]));
+        }
     }
 
     #[test]
-    fn pattern_does_not_backtrack_after_first() {
-        let mut searcher = ascii_chars!(' ').into_searcher("hello w ");
-        assert_eq!(SearchStep::Reject(0,5), searcher.next());
-        assert_eq!(SearchStep::Match(5,6), searcher.next());
-        assert_eq!(SearchStep::Reject(6,7), searcher.next());
-        assert_eq!(SearchStep::Match(7,8), searcher.next());
-        assert_eq!(SearchStep::Done, searcher.next());
+    #[cfg(target_arch = "x86_64")]
+    fn avx2_crosses_32_byte_windows() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        let ac = AsciiChars::from_words(0x0000000000003c20, 0, 2);
+
+        for len in 0..100 {
+            for at in 0..len {
+                let mut s = vec![b'a'; len];
+                s[at] = b'<';
+                assert_eq!(Some(at), unsafe { ac.find_avx2(&s) });
+            }
+            assert_eq!(None, unsafe { ac.find_avx2(&vec![b'a'; len]) });
+        }
     }
 
     #[test]
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn space_is_found() {
-        // Since the algorithm operates on 16-byte chunks, it's
-        // important to cover tests around that boundary. Since 16
-        // isn't that big of a number, we might as well do all of
-        // them.
+    #[cfg(all(feature = "avx512", target_arch = "x86_64"))]
+    fn avx512_crosses_64_byte_windows() {
+        if !is_x86_feature_detected!("avx512bw") {
+            return;
+        }
 
-        assert_eq!(Some(0),  SPACE.find(" "));
-        assert_eq!(Some(1),  SPACE.find("0 "));
-        assert_eq!(Some(2),  SPACE.find("01 "));
-        assert_eq!(Some(3),  SPACE.find("012 "));
-        assert_eq!(Some(4),  SPACE.find("0123 "));
-        assert_eq!(Some(5),  SPACE.find("01234 "));
-        assert_eq!(Some(6),  SPACE.find("012345 "));
-        assert_eq!(Some(7),  SPACE.find("0123456 "));
-        assert_eq!(Some(8),  SPACE.find("01234567 "));
-        assert_eq!(Some(9),  SPACE.find("012345678 "));
-        assert_eq!(Some(10), SPACE.find("0123456789 "));
-        assert_eq!(Some(11), SPACE.find("0123456789A "));
-        assert_eq!(Some(12), SPACE.find("0123456789AB "));
-        assert_eq!(Some(13), SPACE.find("0123456789ABC "));
-        assert_eq!(Some(14), SPACE.find("0123456789ABCD "));
-        assert_eq!(Some(15), SPACE.find("0123456789ABCDE "));
-        assert_eq!(Some(16), SPACE.find("0123456789ABCDEF "));
-        assert_eq!(Some(17), SPACE.find("0123456789ABCDEFG "));
+        let ac = AsciiChars::from_words(0x0000000000003c20, 0, 2);
+
+        for len in 0..200 {
+            for at in 0..len {
+                let mut s = vec![b'a'; len];
+                s[at] = b'<';
+                assert_eq!(Some(at), unsafe { ac.find_avx512(&s) });
+                assert_eq!(Some(at), unsafe { ac.find_bytes_with_lanes::<64>(&s) });
+            }
+            assert_eq!(None, unsafe { ac.find_avx512(&vec![b'a'; len]) });
+        }
+    }
+
+    #[test]
+    #[cfg(not(any(target_arch = "aarch64",
+                  all(target_arch = "wasm32", target_feature = "simd128"))))]
+    fn swar_fallback_works_as_find_does_for_single_characters() {
+        fn prop(s: String, c: AsciiChar) -> bool {
+            ascii_chars!(c.0).find_fallback(s.as_bytes()) == s.find(c.0)
+        }
+        quickcheck(prop as fn(String, AsciiChar) -> bool);
+    }
+
+    #[test]
+    #[cfg(not(any(target_arch = "aarch64",
+                  all(target_arch = "wasm32", target_feature = "simd128"))))]
+    fn swar_fallback_covers_every_byte_of_a_word() {
+        let searcher = ascii_chars!('<');
+
+        for len in 0..40 {
+            for at in 0..len {
+                let mut s = vec![b'a'; len];
+                s[at] = b'<';
+                s[len - 1] = b'<';
+                assert_eq!(Some(at), searcher.find_fallback(&s));
+            }
+            assert_eq!(None, searcher.find_fallback(&vec![b'a'; len]));
+        }
     }
 
     #[test]
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn space_not_found() {
-        // Since the algorithm operates on 16-byte chunks, it's
-        // important to cover tests around that boundary. Since 16
-        // isn't that big of a number, we might as well do all of
-        // them.
+    #[cfg(target_arch = "aarch64")]
+    fn neon_works_as_the_fallback_does_for_1_to_16_characters() {
+        let haystack: Vec<u8> = (0..100).map(|i| b'a' + (i % 20) as u8).collect();
 
-        assert_eq!(None, SPACE.find(""));
-        assert_eq!(None, SPACE.find("0"));
-        assert_eq!(None, SPACE.find("01"));
-        assert_eq!(None, SPACE.find("012"));
-        assert_eq!(None, SPACE.find("0123"));
-        assert_eq!(None, SPACE.find("01234"));
-        assert_eq!(None, SPACE.find("012345"));
-        assert_eq!(None, SPACE.find("0123456"));
-        assert_eq!(None, SPACE.find("01234567"));
-        assert_eq!(None, SPACE.find("012345678"));
-        assert_eq!(None, SPACE.find("0123456789"));
-        assert_eq!(None, SPACE.find("0123456789A"));
-        assert_eq!(None, SPACE.find("0123456789AB"));
-        assert_eq!(None, SPACE.find("0123456789ABC"));
-        assert_eq!(None, SPACE.find("0123456789ABCD"));
-        assert_eq!(None, SPACE.find("0123456789ABCDE"));
-        assert_eq!(None, SPACE.find("0123456789ABCDEF"));
-        assert_eq!(None, SPACE.find("0123456789ABCDEFG"));
+        for n in 1..17 {
+            let mut searcher = AsciiChars::new();
+            for i in 0..n {
+                searcher.push(b'a' + 19 - i as u8);
+            }
+            let fallback = |b: u8| b > b'a' + 19 - n as u8;
+
+            for start in 0..haystack.len() + 1 {
+                let tail = &haystack[start..];
+                assert_eq!(tail.iter().cloned().position(&fallback), searcher.find_neon(tail));
+            }
+            assert_eq!(None, searcher.find_neon(&[b'!'; 40]));
+        }
     }
 
     #[test]
-    #[cfg(all(feature = "unstable", target_arch = "x86_64"))]
-    fn works_on_nonaligned_beginnings() {
-        // We have special code for strings that don't lie on 16-byte
-        // boundaries. Since allocation seems to happen on these
-        // boundaries by default, let's walk around a bit.
+    #[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+    fn simd128_works_as_the_fallback_does_for_multiple_characters() {
+        let haystack: Vec<u8> = (0..100).map(|i| b'a' + (i % 20) as u8).collect();
 
-        let s = "0123456789ABCDEF ".to_string();
+        for n in 1..17 {
+            let mut searcher = AsciiChars::new();
+            for i in 0..n {
+                searcher.push(b'a' + 19 - i as u8);
+            }
+            let fallback = |b: u8| b > b'a' + 19 - n as u8;
 
-        assert_eq!(Some(16), SPACE.find(&s[ 0..]));
-        assert_eq!(Some(15), SPACE.find(&s[ 1..]));
-        assert_eq!(Some(14), SPACE.find(&s[ 2..]));
-        assert_eq!(Some(13), SPACE.find(&s[ 3..]));
-        assert_eq!(Some(12), SPACE.find(&s[ 4..]));
-        assert_eq!(Some(11), SPACE.find(&s[ 5..]));
-        assert_eq!(Some(10), SPACE.find(&s[ 6..]));
-        assert_eq!(Some(9),  SPACE.find(&s[ 7..]));
-        assert_eq!(Some(8),  SPACE.find(&s[ 8..]));
-        assert_eq!(Some(7),  SPACE.find(&s[ 9..]));
-        assert_eq!(Some(6),  SPACE.find(&s[10..]));
-        assert_eq!(Some(5),  SPACE.find(&s[11..]));
-        assert_eq!(Some(4),  SPACE.find(&s[12..]));
-        assert_eq!(Some(3),  SPACE.find(&s[13..]));
-        assert_eq!(Some(2),  SPACE.find(&s[14..]));
-        assert_eq!(Some(1),  SPACE.find(&s[15..]));
-        assert_eq!(Some(0),  SPACE.find(&s[16..]));
-        assert_eq!(None,     SPACE.find(&s[17..]));
+            for start in 0..haystack.len() + 1 {
+                let tail = &haystack[start..];
+                assert_eq!(tail.iter().cloned().position(&fallback), searcher.find_simd128(tail));
+            }
+            assert_eq!(None, searcher.find_simd128(&[b'!'; 40]));
+        }
     }
 
     #[test]
@@ -940,6 +6963,55 @@ mod test {
         //   this "a" is a false positive ~~~~~~~~~~~~~~~^
     }
 
+    /// Calls `f` with a 32 byte haystack of "x"s that starts at every
+    /// possible offset from a 16-byte-aligned block, after `place`
+    /// has written each piece of text at its index.
+    fn with_each_alignment<F>(place: &[(usize, &str)], mut f: F)
+        where F: FnMut(&str)
+    {
+        for alignment in 0..16 {
+            let mut buffer = "x".repeat(64);
+            let start = (alignment + 16 - buffer.as_ptr() as usize % 16) % 16;
+            for &(index, text) in place {
+                let index = start + index;
+                buffer.replace_range(index..index + text.len(), text);
+            }
+            f(&buffer[start..start + 32]);
+        }
+    }
+
+    #[test]
+    fn substring_is_found_across_16_byte_windows() {
+        for offset in 14..18 {
+            for len in 2..9 {
+                let needle = &"abcdefgh"[..len];
+                let substr = Substring::new(needle);
+                with_each_alignment(&[(offset, needle)], |haystack| {
+                    assert_eq!(Some(offset), substr.find(haystack), "{:?} in {:?}", needle, haystack);
+                    assert_eq!(Some(offset), substr.rfind(haystack), "{:?} in {:?}", needle, haystack);
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn substring_false_positive_is_skipped_across_16_byte_windows() {
+        // A partial match right before the real one, so the real
+        // match starts wherever the partial match stopped
+        for offset in 14..18 {
+            for len in 2..9 {
+                let needle = &"abcdefgh"[..len];
+                let partial = &needle[..len - 1];
+                let substr = Substring::new(needle);
+                let place = [(offset - partial.len(), partial), (offset, needle)];
+                with_each_alignment(&place, |haystack| {
+                    assert_eq!(Some(offset), substr.find(haystack), "{:?} in {:?}", needle, haystack);
+                    assert_eq!(Some(offset), substr.rfind(haystack), "{:?} in {:?}", needle, haystack);
+                });
+            }
+        }
+    }
+
     #[test]
     fn substring_needle_is_longer_than_16_bytes() {
         let needle = "0123456789abcdefg";
@@ -947,6 +7019,515 @@ mod test {
         assert_eq!(Some(0), Substring::new(needle).find(haystack));
     }
 
+    #[test]
+    fn works_as_rfind_does_for_substrings() {
+        fn prop(needle: String, haystack: String) -> bool {
+            let s = Substring::new(&needle);
+            s.rfind(&haystack) == haystack.rfind(&needle)
+        }
+        quickcheck(prop as fn(String, String) -> bool);
+
+        fn prop_present(haystack: String, (skip, take): (usize, usize)) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 20).collect();
+            let s = Substring::new(&needle);
+            s.rfind(&haystack) == haystack.rfind(&needle)
+        }
+        quickcheck(prop_present as fn(String, (usize, usize)) -> bool);
+    }
+
+    #[test]
+    fn substring_is_found_in_reverse() {
+        let substr = Substring::new("\r\n");
+        assert_eq!(Some(16), substr.rfind("0123456789ABCD\r\n\r\n"));
+        assert_eq!(Some(15), substr.rfind("\r\n23456789ABCDE\r\nG"));
+        assert_eq!(Some(0), substr.rfind("\r\n23456789ABCDEFG"));
+        assert_eq!(None, substr.rfind("0123456789ABCDEF\r"));
+        assert_eq!(None, substr.rfind(""));
+
+        assert_eq!(Some(0), Substring::new("").rfind(""));
+        assert_eq!(Some(3), Substring::new("").rfind("abc"));
+    }
+
+    #[test]
+    fn substring_has_false_positive_in_reverse() {
+        // Each of these "a"s is only a partial match at the end of a
+        // 16 byte window
+        let substr = Substring::new("ab");
+        assert_eq!(Some(0), substr.rfind("abaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+        assert_eq!(None, substr.rfind("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+    }
+
+    #[test]
+    fn substring_needle_is_longer_than_16_bytes_in_reverse() {
+        let needle = "0123456789abcdefg";
+        let haystack = "0123456789abcdefg0123456789abcdefh";
+        assert_eq!(Some(0), Substring::new(needle).rfind(haystack));
+    }
+
+    #[test]
+    fn split_once_works_as_str_split_once_does() {
+        fn prop(haystack: String, (skip, take): (usize, usize)) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 4).collect();
+            let s = Substring::new(&needle);
+            let bytes = s.split_once_bytes(haystack.as_bytes())
+                .map(|(before, after)| (::std::str::from_utf8(before).unwrap(), ::std::str::from_utf8(after).unwrap()));
+
+            s.split_once(&haystack) == haystack.split_once(&needle[..]) && bytes == s.split_once(&haystack)
+        }
+        quickcheck(prop as fn(String, (usize, usize)) -> bool);
+    }
+
+    #[test]
+    fn split_once_splits_around_the_first_match() {
+        let eq = Substring::new("=");
+        assert_eq!(Some(("key", "value=1")), eq.split_once("key=value=1"));
+        assert_eq!(None, eq.split_once("key"));
+
+        let arrow = Substring::new("=>");
+        assert_eq!(Some((&b"a"[..], &b"b=>c"[..])), arrow.split_once_bytes(b"a=>b=>c"));
+        assert_eq!(Some((&b""[..], &b""[..])), arrow.split_once_bytes(b"=>"));
+        assert_eq!(None, arrow.split_once_bytes(b"a=b>"));
+    }
+
+    #[test]
+    fn strip_prefix_and_suffix_remove_the_substring() {
+        let frame = Substring::new("--");
+        assert_eq!(Some(&b"payload--"[..]), frame.strip_prefix(b"--payload--"));
+        assert_eq!(Some(&b"--payload"[..]), frame.strip_suffix(b"--payload--"));
+        assert_eq!(None, frame.strip_prefix(b"payload--"));
+        assert_eq!(None, frame.strip_suffix(b"--payload"));
+        assert_eq!(None, frame.strip_prefix(b"-"));
+        assert_eq!(None, frame.strip_suffix(b"-"));
+        assert_eq!(Some(&b""[..]), frame.strip_suffix(b"--"));
+
+        let empty = Substring::new("");
+        assert_eq!(Some(&b"abc"[..]), empty.strip_prefix(b"abc"));
+        assert_eq!(Some(&b"abc"[..]), empty.strip_suffix(b"abc"));
+
+        let header = Substring::new_ascii_case_insensitive("http/");
+        assert_eq!(Some(&b"1.1"[..]), header.strip_prefix(b"HTTP/1.1"));
+    }
+
+    #[test]
+    fn strip_prefix_and_suffix_work_as_the_slice_methods_do() {
+        fn prop(needle: String, haystack: String) -> bool {
+            let s = Substring::new(&needle);
+            let (needle, haystack) = (needle.as_bytes(), haystack.as_bytes());
+            s.strip_prefix(haystack) == haystack.strip_prefix(needle) &&
+                s.strip_suffix(haystack) == haystack.strip_suffix(needle)
+        }
+        quickcheck(prop as fn(String, String) -> bool);
+
+        fn prop_present(needle: String, haystack: String) -> bool {
+            let s = Substring::new(&needle);
+            let framed = format!("{}{}{}", needle, haystack, needle);
+            let framed = framed.as_bytes();
+            s.strip_prefix(framed) == framed.strip_prefix(needle.as_bytes()) &&
+                s.strip_suffix(framed) == framed.strip_suffix(needle.as_bytes())
+        }
+        quickcheck(prop_present as fn(String, String) -> bool);
+    }
+
+    #[test]
+    fn find_ranges_works_as_match_indices_does() {
+        fn prop(needle: String, haystack: String) -> bool {
+            let s = Substring::new(&needle);
+            let expected = haystack.match_indices(&needle[..]).map(|(i, m)| i..i + m.len());
+            s.find_ranges(&haystack).eq(expected)
+        }
+        quickcheck(prop as fn(String, String) -> bool);
+
+        fn prop_present(haystack: String, (skip, take): (usize, usize)) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 4).collect();
+            let s = Substring::new(&needle);
+            let expected = haystack.match_indices(&needle[..]).map(|(i, m)| i..i + m.len());
+            s.find_ranges(&haystack).eq(expected)
+        }
+        quickcheck(prop_present as fn(String, (usize, usize)) -> bool);
+    }
+
+    #[test]
+    fn find_ranges_can_slice_the_matches() {
+        let haystack = "aaaaa a";
+        let ranges: Vec<_> = Substring::new("aa").find_ranges(haystack).collect();
+        assert_eq!(vec![0..2, 2..4], ranges);
+        assert!(ranges.iter().all(|r| &haystack[r.clone()] == "aa"));
+
+        let empty: Vec<_> = Substring::new("").find_ranges("aé").collect();
+        assert_eq!(vec![0..0, 1..1, 3..3], empty);
+    }
+
+    #[test]
+    fn find_iter_works_as_match_indices_does() {
+        fn prop(needle: String, haystack: String) -> bool {
+            let s = Substring::new(&needle);
+            s.find_iter(&haystack).eq(haystack.match_indices(&needle[..]).map(|(i, _)| i))
+        }
+        quickcheck(prop as fn(String, String) -> bool);
+
+        fn prop_present(haystack: String, (skip, take): (usize, usize)) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 4).collect();
+            let s = Substring::new(&needle);
+            s.find_iter(&haystack).eq(haystack.match_indices(&needle[..]).map(|(i, _)| i))
+        }
+        quickcheck(prop_present as fn(String, (usize, usize)) -> bool);
+    }
+
+    #[test]
+    fn find_overlapping_iter_finds_every_match() {
+        fn prop(haystack: String, (skip, take): (usize, usize)) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 4).collect();
+            let s = Substring::new(&needle);
+            let expected = (0..haystack.len() + 1)
+                .filter(|&i| haystack.is_char_boundary(i) && haystack[i..].starts_with(&needle[..]));
+            s.find_overlapping_iter(&haystack).eq(expected)
+        }
+        quickcheck(prop as fn(String, (usize, usize)) -> bool);
+    }
+
+    #[test]
+    fn find_iter_works_as_rmatch_indices_does_from_the_back() {
+        fn prop(haystack: String, (skip, take): (usize, usize)) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 4).collect();
+            let s = Substring::new(&needle);
+            s.find_iter(&haystack).rev().eq(haystack.rmatch_indices(&needle[..]).map(|(i, _)| i)) &&
+                s.find_overlapping_iter(&haystack).rev()
+                    .eq(s.find_overlapping_iter(&haystack).collect::<Vec<_>>().into_iter().rev())
+        }
+        quickcheck(prop as fn(String, (usize, usize)) -> bool);
+    }
+
+    #[test]
+    fn find_iter_from_both_ends_yields_each_match_once() {
+        fn prop(haystack: String, (skip, take): (usize, usize), backs: Vec<bool>) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 4).collect();
+            let s = Substring::new(&needle);
+
+            let interleave = |mut iter: super::FindIter| {
+                let mut found = Vec::new();
+                let mut backs = backs.iter().cycle();
+                loop {
+                    let next = if backs.next() == Some(&true) { iter.next_back() } else { iter.next() };
+                    match next {
+                        Some(pos) => found.push(pos),
+                        None => break,
+                    }
+                }
+                assert_eq!(None, iter.next());
+                assert_eq!(None, iter.next_back());
+                found.sort();
+                found
+            };
+
+            let overlapping = interleave(s.find_overlapping_iter(&haystack));
+            let disjoint = interleave(s.find_iter(&haystack));
+
+            overlapping == s.find_overlapping_iter(&haystack).collect::<Vec<_>>() &&
+                disjoint.iter().all(|&i| haystack[i..].starts_with(&needle[..])) &&
+                disjoint.windows(2).all(|w| w[0] + cmp::max(needle.len(), 1) <= w[1])
+        }
+        quickcheck(prop as fn(String, (usize, usize), Vec<bool>) -> bool);
+    }
+
+    #[test]
+    fn find_iter_interleaves_next_and_next_back() {
+        let substr = Substring::new("ab");
+        let mut iter = substr.find_iter("ab-ab-ab-ab");
+        assert_eq!(Some(9), iter.next_back());
+        assert_eq!(Some(0), iter.next());
+        assert_eq!(Some(6), iter.next_back());
+        assert_eq!(Some(3), iter.next());
+        assert_eq!(None, iter.next_back());
+        assert_eq!(None, iter.next());
+
+        let substr = Substring::new("aa");
+        let mut iter = substr.find_iter("aaaaa");
+        assert_eq!(Some(3), iter.next_back());
+        assert_eq!(Some(0), iter.next());
+        assert_eq!(None, iter.next_back());
+
+        let mut iter = substr.find_overlapping_iter("aaaa");
+        assert_eq!(Some(2), iter.next_back());
+        assert_eq!(Some(0), iter.next());
+        assert_eq!(Some(1), iter.next_back());
+        assert_eq!(None, iter.next());
+
+        let empty = Substring::new("");
+        let mut iter = empty.find_iter("aé");
+        assert_eq!(Some(3), iter.next_back());
+        assert_eq!(Some(1), iter.next_back());
+        assert_eq!(Some(0), iter.next());
+        assert_eq!(None, iter.next_back());
+
+        assert_eq!(vec![6..8, 4..6], Substring::new("bc").find_ranges("abcabcbc").rev().take(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn find_iters_distinguish_overlapping_matches() {
+        let substr = Substring::new("aa");
+        assert_eq!(vec![0, 2], substr.find_iter("aaaa").collect::<Vec<_>>());
+        assert_eq!(vec![0, 1, 2], substr.find_overlapping_iter("aaaa").collect::<Vec<_>>());
+
+        let empty = Substring::new("");
+        assert_eq!(vec![0, 1, 3], empty.find_iter("aé").collect::<Vec<_>>());
+        assert_eq!(vec![0], empty.find_overlapping_iter("").collect::<Vec<_>>());
+
+        let mut iter = substr.find_iter("aa");
+        assert_eq!(Some(0), iter.next());
+        assert_eq!(None, iter.next());
+        assert_eq!(None, iter.next());
+    }
+
+    #[test]
+    #[cfg(feature = "winnow")]
+    fn winnow_parsers_consume_up_to_the_match() {
+        use winnow::error::{ErrMode, InputError};
+        use winnow::Parser;
+        use super::{take_till_bytes, take_until_substring};
+
+        let mut input = &b"key=value\r\n\r\nbody"[..];
+        let key = take_till_bytes::<InputError<_>>(AsciiChars::from_bytes(b"=:")).parse_next(&mut input);
+        assert_eq!(Ok(&b"key"[..]), key);
+        assert_eq!(b"=value\r\n\r\nbody", input);
+
+        let mut headers = take_until_substring::<InputError<_>>(Substring::new("\r\n\r\n"));
+        assert_eq!(Ok(&b"=value"[..]), headers.parse_next(&mut input));
+        assert_eq!(b"\r\n\r\nbody", input);
+
+        let mut input = &b"no delimiters"[..];
+        let missing = take_till_bytes::<InputError<_>>(AsciiChars::from_bytes(b"=")).parse_next(&mut input);
+        assert!(matches!(missing, Err(ErrMode::Backtrack(_))));
+        assert_eq!(b"no delimiters", input);
+    }
+
+    #[test]
+    #[cfg(feature = "bstr")]
+    fn substring_searches_bstr_by_byte_index() {
+        use bstr::BStr;
+
+        let haystack = BStr::new(b"\xffab\xfeab");
+        let substr = Substring::new("ab");
+        assert_eq!(Some(1), substr.find_bstr(haystack));
+        assert_eq!(vec![1, 4], substr.find_iter_bstr(haystack).collect::<Vec<_>>());
+        assert_eq!(None, substr.find_bstr(BStr::new("a b")));
+
+        let empty = Substring::new("");
+        assert_eq!(vec![0, 1, 2], empty.find_iter_bstr(BStr::new(b"\xff\xfe")).collect::<Vec<_>>());
+
+        let chars = AsciiChars::from_bytes(b"ab");
+        assert_eq!(Some(1), chars.find_bytes(haystack));
+        assert_eq!(vec![(1, b'a'), (2, b'b'), (4, b'a'), (5, b'b')],
+                   chars.match_indices(haystack).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn find_word_requires_boundaries_around_the_match() {
+        let is_word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+        let substr = Substring::new("if");
+
+        assert_eq!(Some(0), substr.find_word(b"if x", is_word));
+        assert_eq!(Some(8), substr.find_word(b"sniff; (if", is_word));
+        assert_eq!(Some(10), substr.find_word(b"iffy, if_ if", is_word));
+        assert_eq!(None, substr.find_word(b"sniff _if_ iff", is_word));
+        assert_eq!(None, substr.find_word(b"", is_word));
+
+        let word_chars = AsciiChars::from_bytes(b"abcdefghijklmnop");
+        assert_eq!(Some(23), substr.find_word(b"0123456789abcdefghijif-if", |b| word_chars.contains_byte(b)));
+
+        assert_eq!(Some(3), Substring::new("").find_word(b"ab  cd", is_word));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_set_finds_the_earliest_needle() {
+        let set = SubstringSet::new(&["<!--", "<?", "<![CDATA["]);
+        assert_eq!(Some((3, 1)), set.find("abc<?xml?><!-- -->"));
+        assert_eq!(Some((0, 0)), set.find("<!-- <![CDATA[ -->"));
+        assert_eq!(Some((0, 2)), set.find("<![CDATA[ <!-- -->"));
+        assert_eq!(None, set.find("<a><b/></a>"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_set_prefers_the_first_needle_at_the_same_index() {
+        let set = SubstringSet::new(&["<!", "<", "<!--"]);
+        assert_eq!(Some((2, 0)), set.find("ab<!--"));
+        assert_eq!(Some((2, 1)), set.find("ab<a"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_set_without_needles_finds_nothing() {
+        assert_eq!(None, SubstringSet::new(&[]).find("anything"));
+        assert_eq!(None, SubstringSet::new(&[]).find(""));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_builder_builds_what_new_would() {
+        let mut builder = SubstringBuilder::new();
+        assert_eq!(Err(EmptyNeedle), builder.build().map(|s| s.find("abc")));
+
+        builder.push_str("<!");
+        builder.push_byte(b'-');
+        builder.extend(vec!["-", ""]);
+
+        let built = builder.build().unwrap();
+        let whole = Substring::new("<!--");
+        for haystack in &["", "<!-", "a <!-- b <!-- c", "<!--", "<!—"] {
+            assert_eq!(whole.find(haystack), built.find(haystack));
+            assert_eq!(whole.rfind(haystack), built.rfind(haystack));
+        }
+        assert_eq!(Some(2), built.find("a <!-- b"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    #[should_panic(expected = "Substring can only be built from ASCII bytes, not 0xc3")]
+    fn substring_builder_rejects_non_ascii_bytes() {
+        SubstringBuilder::new().push_byte(0xc3);
+    }
+
+    #[test]
+    fn case_insensitive_substring_works_as_find_does_on_lowercase() {
+        fn prop(haystack: String, (skip, take): (usize, usize), flip: Vec<bool>) -> bool {
+            // Only change the case of needles taken from ASCII text, as
+            // lowercasing can change the length of other characters
+            let haystack: String = haystack.chars().filter(char::is_ascii).collect();
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 6).enumerate()
+                .map(|(i, c)| if flip.get(i) == Some(&true) { c.to_ascii_uppercase() } else { c })
+                .collect();
+            let s = Substring::new_ascii_case_insensitive(&needle);
+
+            let lower = haystack.to_ascii_lowercase();
+            let lower_needle = needle.to_ascii_lowercase();
+            s.find(&haystack) == lower.find(&lower_needle) &&
+            s.rfind(&haystack) == lower.rfind(&lower_needle)
+        }
+        quickcheck(prop as fn(String, (usize, usize), Vec<bool>) -> bool);
+    }
+
+    #[test]
+    fn case_insensitive_substring_ignores_case_of_ascii_letters_only() {
+        let substr = Substring::new_ascii_case_insensitive("Content-Type:");
+        assert_eq!(Some(5), substr.find("Host\ncontent-type: text/html"));
+        assert_eq!(Some(0), substr.find("CONTENT-TYPE:"));
+        assert_eq!(None, substr.find("content_type:"));
+        assert_eq!(Some(14), substr.rfind("CONTENT-TYPE: content-type:"));
+
+        let substr = Substring::new_ascii_case_insensitive("café");
+        assert_eq!(Some(2), substr.find("a CAFé"));
+        assert_eq!(None, substr.find("a CAFÉ"));
+
+        let parts: Vec<_> = "aXbxc".split(Substring::new_ascii_case_insensitive("x")).collect();
+        assert_eq!(&parts, &["a", "b", "c"]);
+
+        assert_eq!(Some(0), Substring::new_ascii_case_insensitive("").find("abc"));
+        assert_eq!(Some(3), Substring::new_ascii_case_insensitive("").rfind("abc"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_find_in_reader_works_across_chunks() {
+        fn prop(haystack: String, (skip, take): (usize, usize), capacity: usize) -> bool {
+            use std::io::{BufReader, Read};
+
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 6 + 1).collect();
+            let s = Substring::new(&needle);
+            let mut reader = BufReader::with_capacity(capacity % 8 + 1, haystack.as_bytes());
+
+            let found = s.find_in_reader(&mut reader).unwrap();
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+
+            let expected = haystack.find(&needle[..]);
+            let expected_rest = expected.map_or("", |idx| &haystack[idx + needle.len()..]);
+            found == expected && rest == expected_rest
+        }
+        quickcheck(prop as fn(String, (usize, usize), usize) -> bool);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_find_in_reader_carries_partial_matches() {
+        use std::io::BufReader;
+
+        let substr = Substring::new("\r\n\r\n");
+        let mut reader = BufReader::with_capacity(2, &b"ab\r\n\rx\r\n\r\nbody"[..]);
+        assert_eq!(Some(6), substr.find_in_reader(&mut reader).unwrap());
+        assert_eq!(None, substr.find_in_reader(&mut reader).unwrap());
+
+        let mut reader = BufReader::with_capacity(4, &b""[..]);
+        assert_eq!(None, substr.find_in_reader(&mut reader).unwrap());
+        assert_eq!(Some(0), Substring::new("").find_in_reader(&mut reader).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn ascii_chars_find_in_reader_consumes_through_the_match() {
+        use std::io::{BufRead, BufReader};
+
+        let delimiters = AsciiChars::from_bytes(b",;");
+        let mut reader = BufReader::with_capacity(4, &b"abcdefg;hij,k"[..]);
+        assert_eq!(Some(7), delimiters.find_in_reader(&mut reader).unwrap());
+        assert_eq!(Some(3), delimiters.find_in_reader(&mut reader).unwrap());
+        assert_eq!(b"k", reader.fill_buf().unwrap());
+        assert_eq!(None, delimiters.find_in_reader(&mut reader).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_find_in_chunks_works_across_chunks() {
+        fn prop(haystack: String, (skip, take): (usize, usize), size: usize) -> bool {
+            let needle: String = haystack.chars().skip(skip % 40).take(take % 6 + 1).collect();
+            let chunks = haystack.as_bytes().chunks(size % 8 + 1);
+            Substring::new(&needle).find_in_chunks(chunks) == haystack.find(&needle[..])
+        }
+        quickcheck(prop as fn(String, (usize, usize), usize) -> bool);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn substring_find_in_chunks_carries_partial_matches() {
+        let substr = Substring::new("\r\n\r\n");
+        let chunks: [&[u8]; 5] = [b"ab\r", b"\n\rx", b"\r", b"", b"\n\r\nbody"];
+        assert_eq!(Some(6), substr.find_in_chunks(chunks.iter().cloned()));
+        assert_eq!(None, substr.find_in_chunks(chunks[..3].iter().cloned()));
+        assert_eq!(None, substr.find_in_chunks(None));
+        assert_eq!(Some(0), Substring::new("").find_in_chunks(None));
+    }
+
+    #[test]
+    fn match_mask_has_a_bit_for_each_matching_byte() {
+        let chars = AsciiChars::from_bytes(b"<>&");
+        assert_eq!(0b1000_0000_0000_1001, chars.match_mask(b"<bc>abcdefghijk&"));
+        assert_eq!(0, chars.match_mask(b"no matches here\xff"));
+        assert_eq!(0xffff, chars.match_mask(b"<<<<>>>>&&&&<>&<"));
+        assert_eq!(0, AsciiChars::new().match_mask(b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"));
+
+        fn prop(window: (u64, u64), v: Vec<AsciiChar>) -> bool {
+            let mut bytes = [0; 16];
+            bytes[..8].copy_from_slice(&window.0.to_le_bytes());
+            bytes[8..].copy_from_slice(&window.1.to_le_bytes());
+            let n = cmp::min(super::MAXBYTES as usize, v.len());
+            let needle: Vec<_> = v[..n].iter().map(|c| c.0 as u8).collect();
+            let chars = AsciiChars::from_bytes(&needle);
+
+            let mask = chars.match_mask(&bytes);
+            (0..16).all(|i| (mask >> i & 1 != 0) == chars.contains_byte(bytes[i]))
+        }
+        quickcheck(prop as fn((u64, u64), Vec<AsciiChar>) -> bool);
+    }
+
+    #[test]
+    fn ascii_chars_find_in_chunks_counts_from_the_first_chunk() {
+        let delimiters = AsciiChars::from_bytes(b",;");
+        let chunks: [&[u8]; 4] = [b"abc", b"", b"defg;hi", b"j,k"];
+        assert_eq!(Some(7), delimiters.find_in_chunks(chunks.iter().cloned()));
+        assert_eq!(Some(1), delimiters.find_in_chunks(chunks[3..].iter().cloned()));
+        assert_eq!(None, delimiters.find_in_chunks(chunks[..2].iter().cloned()));
+    }
+
     #[test]
     fn substring_as_pattern() {
         let needle = "and";
@@ -954,6 +7535,26 @@ mod test {
         let parts: Vec<_> = haystack.split(Substring::new(needle)).collect();
         assert_eq!(&parts, &["moats ", " boats ", " waterfalls"]);
     }
+
+    #[test]
+    fn empty_substring_as_pattern_matches_at_every_boundary() {
+        for haystack in &["", "a", "aé\u{1F600}b"] {
+            let parts: Vec<_> = haystack.split(Substring::new("")).collect();
+            let expected: Vec<_> = haystack.split("").collect();
+            assert_eq!(expected, parts);
+
+            let indices: Vec<_> = haystack.match_indices(Substring::new("")).map(|(i, _)| i).collect();
+            let expected: Vec<_> = haystack.match_indices("").map(|(i, _)| i).collect();
+            assert_eq!(expected, indices);
+        }
+    }
+
+    #[test]
+    fn substring_try_new_refuses_empty_needles() {
+        assert_eq!(Err(EmptyNeedle), Substring::try_new("").map(|s| s.find("abc")));
+        assert_eq!(Ok(Some(1)), Substring::try_new("bc").map(|s| s.find("abc")));
+        assert_eq!(Some(0), Substring::new("").find("abc"));
+    }
 }
 
 #[cfg(test)]
@@ -994,6 +7595,16 @@ mod bench {
         bench_space(b, |hs| hs.find(ascii_chars!(' ')))
     }
 
+    #[bench]
+    fn space_asciichars_find_bytes(b: &mut test::Bencher) {
+        bench_space(b, |hs| SPACE.find_bytes(hs))
+    }
+
+    #[bench]
+    fn space_asciichars_prefetched(b: &mut test::Bencher) {
+        bench_space(b, |hs| SPACE.find_bytes_prefetched(hs, 4096))
+    }
+
     #[bench]
     fn space_find_string(b: &mut test::Bencher) {
         bench_space(b, |hs| hs.find(" "))
@@ -1019,6 +7630,28 @@ mod bench {
         bench_space(b, |hs| hs.find(|c| c == ' '))
     }
 
+    fn bench_newlines<F>(b: &mut test::Bencher, f: F)
+        where F: Fn(&[u8]) -> usize
+    {
+        let haystack: Vec<_> = prefix_string().bytes()
+            .enumerate()
+            .map(|(i, b)| if i % 80 == 79 { b'\n' } else { b })
+            .collect();
+
+        b.iter(|| test::black_box(f(&haystack)));
+        b.bytes = haystack.len() as u64;
+    }
+
+    #[bench]
+    fn newlines_count_byte(b: &mut test::Bencher) {
+        bench_newlines(b, |hs| super::count_byte(b'\n', hs))
+    }
+
+    #[bench]
+    fn newlines_filter(b: &mut test::Bencher) {
+        bench_newlines(b, |hs| hs.iter().filter(|&&v| v == b'\n').count())
+    }
+
     fn bench_xml_delim_3<F>(b: &mut test::Bencher, f: F)
         where F: Fn(&str) -> Option<usize>
     {